@@ -7,6 +7,9 @@ use std::str::Utf8Error;
 pub enum RequestError {
     JsonError(JsonError),
     Utf8Error(Utf8Error),
+    MissingParameter(String),
+    InvalidParameter(String, String),
+    QueryError(QueryError),
 }
 
 impl fmt::Display for RequestError {
@@ -14,6 +17,11 @@ impl fmt::Display for RequestError {
         match self {
             RequestError::JsonError(e) => write!(f, "JSON Error: {}", e),
             RequestError::Utf8Error(e) => write!(f, "UTF-8 Error: {}", e),
+            RequestError::MissingParameter(name) => write!(f, "Missing parameter: {}", name),
+            RequestError::InvalidParameter(name, e) => {
+                write!(f, "Invalid parameter {}: {}", name, e)
+            }
+            RequestError::QueryError(e) => write!(f, "{}", e),
         }
     }
 }
@@ -31,3 +39,107 @@ impl From<Utf8Error> for RequestError {
         RequestError::Utf8Error(e)
     }
 }
+
+impl From<QueryError> for RequestError {
+    fn from(e: QueryError) -> Self {
+        RequestError::QueryError(e)
+    }
+}
+
+/// Why a query string could not be deserialized, and for which field.
+#[derive(Debug)]
+pub struct QueryError {
+    field: Option<String>,
+    message: String,
+}
+
+impl QueryError {
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub(crate) fn with_field(mut self, field: &str) -> Self {
+        if self.field.is_none() {
+            self.field = Some(field.to_string());
+        }
+        self
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "Query Error: {}: {}", field, self.message),
+            None => write!(f, "Query Error: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl serde::de::Error for QueryError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        QueryError {
+            field: None,
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        QueryError {
+            field: Some(field.to_string()),
+            message: "missing field".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    WildcardNotLast(String),
+    InvalidConstraint(String, String),
+    Duplicate(String, String),
+    Conflict(String, String, String),
+    DuplicateName(String),
+    InvalidHost(String),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteError::WildcardNotLast(path) => {
+                write!(
+                    f,
+                    "Route Error: wildcard must be the last segment in {}",
+                    path
+                )
+            }
+            RouteError::InvalidConstraint(path, e) => {
+                write!(
+                    f,
+                    "Route Error: invalid parameter constraint in {}: {}",
+                    path, e
+                )
+            }
+            RouteError::Duplicate(method, path) => {
+                write!(f, "Route Error: {} {} is registered twice", method, path)
+            }
+            RouteError::Conflict(method, path, existing) => write!(
+                f,
+                "Route Error: {} {} is ambiguous with {}",
+                method, path, existing
+            ),
+            RouteError::DuplicateName(name) => {
+                write!(f, "Route Error: the route name {} is used twice", name)
+            }
+            RouteError::InvalidHost(pattern) => {
+                write!(f, "Route Error: invalid host pattern {}", pattern)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
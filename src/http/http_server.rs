@@ -2,7 +2,7 @@
 
 use std::io::{self, Read, Write};
 use std::mem::MaybeUninit;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 use bytes::{Buf, BytesMut};
 
@@ -15,6 +15,7 @@ use may::{coroutine, go};
 
 use crate::request::request::RawRequest;
 use crate::response::response::Response;
+use crate::server::access_log::{AccessEntry, AccessLog};
 
 const BUF_LEN: usize = 4096 * 8;
 
@@ -32,8 +33,13 @@ macro_rules! t_c {
 
 pub trait HttpService {
     fn handler(&mut self, req: RawRequest, rsp: &mut Response) -> io::Result<()>;
+
+    fn access_logger(&self) -> Option<&AccessLog> {
+        None
+    }
 }
 
+#[allow(dead_code)]
 pub trait HttpServiceFactory: Send + Sized + 'static {
     type Service: HttpService + Send;
 
@@ -120,17 +126,49 @@ pub(crate) fn reserve_buf(buf: &mut BytesMut) {
 
 pub struct HttpServer<T>(pub T);
 
+// runs the service for one request and encodes its response into `res_buf`
+pub(crate) fn serve<T: HttpService>(
+    service: &mut T,
+    req: RawRequest,
+    peer_addr: Option<SocketAddr>,
+    body_buf: &mut BytesMut,
+    res_buf: &mut BytesMut,
+) {
+    use crate::response::response::{encode, encode_error};
+
+    let entry = service
+        .access_logger()
+        .map(|_| AccessEntry::new(&req, peer_addr));
+    let mut rsp = Response::new(body_buf);
+    let (status, bytes, request_id) = match service.handler(req, &mut rsp) {
+        Ok(()) => {
+            let logged = (rsp.status(), rsp.sent_len(), rsp.take_request_id());
+            encode(rsp, res_buf);
+            logged
+        }
+        Err(e) => {
+            let request_id = rsp.take_request_id();
+            eprintln!("service err = {:?}, request_id = {:?}", e, request_id);
+            let bytes = encode_error(e, request_id.as_deref(), res_buf);
+            (500, bytes, request_id)
+        }
+    };
+
+    if let (Some(access_log), Some(entry)) = (service.access_logger(), entry) {
+        access_log.record(&entry, status, bytes, request_id.as_deref());
+    }
+}
+
 #[cfg(unix)]
 fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T) -> io::Result<()> {
-    use crate::{request, response};
+    use crate::request;
 
     let mut req_buf = BytesMut::with_capacity(BUF_LEN);
     let mut res_buf = BytesMut::with_capacity(BUF_LEN);
     let mut body_buf = BytesMut::with_capacity(BUF_LEN);
+    let peer_addr = stream.peer_addr().ok();
 
     loop {
-        stream.reset_io();
-
         let inner_stream = stream.inner_mut();
 
         // write out the responses
@@ -148,14 +186,7 @@ fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T)
                     Some(req) => req,
                     None => break,
                 };
-                let mut rsp = Response::new(&mut body_buf);
-                match service.handler(req, &mut rsp) {
-                    Ok(()) => response::response::encode(rsp, &mut res_buf),
-                    Err(e) => {
-                        eprintln!("service err = {:?}", e);
-                        response::response::encode_error(e, &mut res_buf);
-                    }
-                }
+                serve(&mut service, req, peer_addr, &mut body_buf, &mut res_buf);
             }
         }
 
@@ -167,11 +198,12 @@ fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T)
 
 #[cfg(not(unix))]
 fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T) -> io::Result<()> {
-    use crate::{request, response};
+    use crate::request;
 
     let mut req_buf = BytesMut::with_capacity(BUF_LEN);
     let mut res_buf = BytesMut::with_capacity(BUF_LEN);
     let mut body_buf = BytesMut::with_capacity(BUF_LEN);
+    let peer_addr = stream.peer_addr().ok();
 
     loop {
         // Ensure there is enough space in the buffer
@@ -196,14 +228,7 @@ fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T)
                     Some(req) => req,
                     None => break,
                 };
-                let mut rsp = Response::new(&mut body_buf);
-                match service.handler(req, &mut rsp) {
-                    Ok(()) => response::response::encode(rsp, &mut res_buf),
-                    Err(e) => {
-                        eprintln!("service err = {:?}", e);
-                        response::response::encode_error(e, &mut res_buf);
-                    }
-                }
+                serve(&mut service, req, peer_addr, &mut body_buf, &mut res_buf);
             }
         }
 
@@ -1,8 +1,13 @@
+#![allow(clippy::module_inception)]
+
 #[macro_use]
 extern crate log;
 
 pub mod server {
+    pub mod access_log;
     pub mod server;
+    #[cfg(test)]
+    pub(crate) mod testing;
 }
 
 mod http {
@@ -10,7 +15,9 @@ mod http {
 }
 
 mod request {
+    pub mod query;
     pub mod request;
+    pub mod url;
 }
 
 mod response {
@@ -26,8 +33,27 @@ mod errors {
     pub mod errors;
 }
 
-use response::response::Response;
+mod middleware {
+    pub mod auth;
+    #[cfg(feature = "compression")]
+    pub mod compression;
+    pub mod cors;
+    pub mod rate_limit;
+}
 
-pub use server::server::{Middleware, RouteHandler, Server};
+pub use middleware::auth::{constant_time_eq, BasicAuth, BearerAuth};
+#[cfg(feature = "compression")]
+pub use middleware::compression::Compression;
+pub use middleware::cors::Cors;
+pub use middleware::rate_limit::RateLimit;
+pub use request::request::{RawRequest, Request};
+pub use response::response::Response;
+pub use router::route_matcher::{Route, RouteInfo};
+pub use server::access_log::{AccessLog, LogFormat};
+pub use server::server::{
+    AfterHook, AroundMiddleware, Group, Middleware, Next, RouteHandler, Router, Server, SlashPolicy,
+};
+
+pub use errors::errors::{QueryError, RequestError, RouteError};
 
 pub use serde_json::json;
@@ -0,0 +1,322 @@
+use std::any::Any;
+use std::io;
+use std::sync::Arc;
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::server::Next;
+
+type BasicVerifier = dyn Fn(&str, &str) -> bool + Send + Sync;
+type BearerVerifier<P> = dyn Fn(&str) -> Option<P> + Send + Sync;
+
+/// HTTP Basic authentication, installed with `Server::around` or
+/// `Route::with`:
+///
+/// ```
+/// # use server_nano::{BasicAuth, Server};
+/// let mut app = Server::new();
+/// app.get("/admin", |req, res| {
+///     let user = req.principal::<String>().cloned().unwrap_or_default();
+///     res.send(format!("hello {}", user))
+/// })
+/// .with(BasicAuth::user("admin", "admin", "secret").into_middleware());
+/// ```
+///
+/// Requests without valid credentials get a 401 with a `WWW-Authenticate`
+/// challenge; otherwise the user name is attached to the request as its
+/// `String` principal.
+#[derive(Clone)]
+pub struct BasicAuth {
+    realm: String,
+    verifier: Arc<BasicVerifier>,
+}
+
+impl BasicAuth {
+    /// Checks credentials with `verifier`, which receives the user name and
+    /// the password. Compare secrets with `constant_time_eq`.
+    pub fn new<F>(realm: &str, verifier: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        BasicAuth {
+            realm: realm.to_string(),
+            verifier: Arc::new(verifier),
+        }
+    }
+
+    /// Accepts a single user name and password.
+    pub fn user(realm: &str, user: &str, password: &str) -> Self {
+        let (user, password) = (user.to_string(), password.to_string());
+        BasicAuth::new(realm, move |u, p| {
+            // evaluate both so a wrong user takes as long as a wrong password
+            let user_ok = constant_time_eq(u.as_bytes(), user.as_bytes());
+            let password_ok = constant_time_eq(p.as_bytes(), password.as_bytes());
+            user_ok & password_ok
+        })
+    }
+
+    pub fn into_middleware(
+        self,
+    ) -> impl Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static {
+        move |req, res, next| self.handle(req, res, next)
+    }
+
+    fn handle(&self, req: &mut Request, res: &mut Response, next: Next) -> io::Result<()> {
+        let user = req
+            .header("authorization")
+            .and_then(|header| credentials(header, "Basic"))
+            .and_then(base64_decode)
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (user, password) = decoded.split_once(':')?;
+                (self.verifier)(user, password).then(|| user.to_string())
+            });
+
+        match user {
+            Some(user) => {
+                req.set_principal(user);
+                next.run(req, res)
+            }
+            None => {
+                res.status_code(401, "Unauthorized");
+                res.header(format!(
+                    "WWW-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"",
+                    quote(&self.realm)
+                ));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Bearer token authentication. The verifier turns a token into a
+/// principal of any type, which handlers read with `Request::principal`:
+///
+/// ```
+/// # use server_nano::{BearerAuth, Server};
+/// struct User(u64);
+///
+/// let mut app = Server::new();
+/// app.around(
+///     BearerAuth::new("api", |token| (token == "t0k3n").then_some(User(1))).into_middleware(),
+/// );
+/// app.get("/me", |req, res| {
+///     let user = req.principal::<User>().map_or(0, |user| user.0);
+///     res.send(user.to_string())
+/// });
+/// ```
+///
+/// Requests without a token, or with one the verifier rejects, get a 401
+/// with a `WWW-Authenticate` challenge.
+pub struct BearerAuth<P> {
+    realm: String,
+    verifier: Arc<BearerVerifier<P>>,
+}
+
+impl<P> Clone for BearerAuth<P> {
+    fn clone(&self) -> Self {
+        BearerAuth {
+            realm: self.realm.clone(),
+            verifier: Arc::clone(&self.verifier),
+        }
+    }
+}
+
+impl<P: Any + Send + Sync> BearerAuth<P> {
+    pub fn new<F>(realm: &str, verifier: F) -> Self
+    where
+        F: Fn(&str) -> Option<P> + Send + Sync + 'static,
+    {
+        BearerAuth {
+            realm: realm.to_string(),
+            verifier: Arc::new(verifier),
+        }
+    }
+
+    pub fn into_middleware(
+        self,
+    ) -> impl Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static {
+        move |req, res, next| self.handle(req, res, next)
+    }
+
+    fn handle(&self, req: &mut Request, res: &mut Response, next: Next) -> io::Result<()> {
+        let token = req
+            .header("authorization")
+            .and_then(|header| credentials(header, "Bearer"));
+        let principal = token.and_then(|token| (self.verifier)(token));
+
+        match principal {
+            Some(principal) => {
+                req.set_principal(principal);
+                next.run(req, res)
+            }
+            None => {
+                // only report an error when a token was actually sent
+                let error = match token {
+                    Some(_) => ", error=\"invalid_token\"",
+                    None => "",
+                };
+                res.status_code(401, "Unauthorized");
+                res.header(format!(
+                    "WWW-Authenticate: Bearer realm=\"{}\"{}",
+                    quote(&self.realm),
+                    error
+                ));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths,
+/// so that comparing secrets does not leak how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// the credentials of an `Authorization` header using `scheme`
+fn credentials<'a>(header: &'a str, scheme: &str) -> Option<&'a str> {
+    let (found, credentials) = header.trim().split_once(' ')?;
+    found
+        .eq_ignore_ascii_case(scheme)
+        .then(|| credentials.trim())
+        .filter(|credentials| !credentials.is_empty())
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Decodes standard, padded base64 as used by Basic authentication.
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0;
+        for &c in &chunk[..4 - padding] {
+            bits = bits << 6 | value(c)?;
+        }
+        bits <<= 6 * padding as u32;
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::server::Server;
+    use crate::server::testing::{send, Sent};
+
+    struct User(u64);
+
+    fn get(app: &mut Server, authorization: Option<&str>) -> Sent {
+        let header = authorization.map_or(String::new(), |value| {
+            format!("Authorization: {}\r\n", value)
+        });
+        send(app, &format!("GET /me HTTP/1.1\r\n{}\r\n", header))
+    }
+
+    #[test]
+    fn basic_auth_challenges_or_attaches_the_user() {
+        let mut app = Server::new();
+        app.get("/me", |req, res| {
+            let user = req.principal::<String>().cloned().unwrap_or_default();
+            res.send(format!("hello {}", user))
+        })
+        .with(BasicAuth::user("the \"admin\" area", "Aladdin", "open sesame").into_middleware());
+
+        let challenge = r#"Basic realm="the \"admin\" area", charset="UTF-8""#;
+        for authorization in [
+            None,
+            Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ"),
+            Some("Basic QWxhZGRpbjp3cm9uZw=="),
+            Some("Bearer QWxhZGRpbjpvcGVuIHNlc2FtZQ=="),
+        ] {
+            let res = get(&mut app, authorization);
+            assert_eq!(res.status, 401, "{:?}", authorization);
+            assert_eq!(res.header("www-authenticate"), Some(challenge));
+            assert_eq!(res.body(), "");
+        }
+
+        let res = get(&mut app, Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="));
+        assert_eq!(res.status, 200);
+        assert_eq!(res.header("www-authenticate"), None);
+        assert_eq!(res.body(), "hello Aladdin");
+    }
+
+    #[test]
+    fn bearer_auth_reports_invalid_tokens_only_when_sent() {
+        let mut app = Server::new();
+        app.around(
+            BearerAuth::new("api", |token| (token == "t0k3n").then_some(User(7))).into_middleware(),
+        );
+        app.get("/me", |req, res| {
+            let user = req.principal::<User>().map_or(0, |user| user.0);
+            res.send(user.to_string())
+        });
+
+        let res = get(&mut app, None);
+        assert_eq!(res.status, 401);
+        assert_eq!(
+            res.header("www-authenticate"),
+            Some(r#"Bearer realm="api""#)
+        );
+
+        // Basic credentials are not a bearer token
+        let res = get(&mut app, Some("Basic dTpw"));
+        assert_eq!(res.status, 401);
+        assert_eq!(
+            res.header("www-authenticate"),
+            Some(r#"Bearer realm="api""#)
+        );
+
+        let res = get(&mut app, Some("Bearer wrong"));
+        assert_eq!(res.status, 401);
+        assert_eq!(
+            res.header("www-authenticate"),
+            Some(r#"Bearer realm="api", error="invalid_token""#)
+        );
+
+        let res = get(&mut app, Some("Bearer t0k3n"));
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body(), "7");
+    }
+
+    #[test]
+    fn decodes_basic_credentials() {
+        let header = "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==";
+        let decoded = credentials(header, "Basic").and_then(base64_decode);
+        assert_eq!(decoded.as_deref(), Some(&b"Aladdin:open sesame"[..]));
+
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode("YWI="), Some(b"ab".to_vec()));
+        assert_eq!(base64_decode("YQ==YQ=="), None);
+        assert_eq!(base64_decode("Y!=="), None);
+        assert_eq!(credentials("Bearer abc", "Basic"), None);
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+    }
+}
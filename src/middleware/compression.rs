@@ -0,0 +1,305 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::server::Next;
+
+/// Compresses response bodies with the best encoding the client accepts,
+/// installed with `Server::around`. Requires the `compression` feature.
+///
+/// ```
+/// # use server_nano::{Compression, Server};
+/// let mut app = Server::new();
+/// app.around(Compression::new().min_size(512).into_middleware());
+/// ```
+///
+/// Only bodies of a textual content type and at least `min_size` bytes are
+/// compressed; responses that already have a `Content-Encoding` are left
+/// untouched. The encoders need far more stack than a connection coroutine
+/// has, so each body is compressed on a coroutine of its own.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { min_size: 1024 }
+    }
+}
+
+// the brotli and deflate encoders need far more stack than a handler does
+const ENCODER_STACK_SIZE: usize = 512 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // in order of preference when the client weighs them equally
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(body)?;
+                Ok(encoder.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Encodes `body` on a coroutine with a stack large enough for the
+    /// encoder, so the connection's own stack can stay small.
+    fn encode_on_large_stack(self, body: Vec<u8>) -> io::Result<Vec<u8>> {
+        if !may::coroutine::is_coroutine() {
+            // a thread's stack is large enough already
+            return self.encode(&body);
+        }
+        let builder = may::coroutine::Builder::new()
+            .stack_size(ENCODER_STACK_SIZE / std::mem::size_of::<usize>());
+        // the encoders touch no thread locals, and the stack is sized for them
+        let encoder = unsafe { builder.spawn(move || self.encode(&body)) }?;
+        encoder
+            .join()
+            .map_err(|_| io::Error::other("the response encoder panicked"))?
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Compression::default()
+    }
+
+    /// The smallest body, in bytes, worth compressing. Defaults to 1024.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn into_middleware(
+        self,
+    ) -> impl Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static {
+        move |req, res, next| self.handle(req, res, next)
+    }
+
+    fn handle(&self, req: &mut Request, res: &mut Response, next: Next) -> io::Result<()> {
+        let encoding = req.header("accept-encoding").and_then(negotiate);
+        next.run(req, res)?;
+
+        let compressible = !matches!(res.status(), 204 | 304)
+            && res.get_header("content-encoding").is_none()
+            && res
+                .get_header("content-type")
+                .is_some_and(compressible_type);
+        if !compressible || res.get_body().len() < self.min_size {
+            return Ok(());
+        }
+
+        res.header("Vary: Accept-Encoding");
+        if let Some(encoding) = encoding {
+            // the body length, and so Content-Length, follows the new body
+            let body = encoding.encode_on_large_stack(res.get_body().to_vec())?;
+            res.body_vec(body);
+            res.header(format!("Content-Encoding: {}", encoding.name()));
+        }
+        Ok(())
+    }
+}
+
+/// The supported encoding with the highest `q` value in an
+/// `Accept-Encoding` header, if any is acceptable.
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut weights = [None; 3];
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if coding == "*" {
+            wildcard = Some(q);
+        } else if let Some(index) = Encoding::ALL
+            .iter()
+            .position(|encoding| coding.eq_ignore_ascii_case(encoding.name()))
+        {
+            weights[index] = Some(q);
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for (encoding, weight) in Encoding::ALL.into_iter().zip(weights) {
+        let q = weight.or(wildcard).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn compressible_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::*;
+    use crate::server::server::Server;
+    use crate::server::testing::{send, Sent};
+
+    fn text() -> String {
+        "all work and no play makes jack a dull boy\n".repeat(64)
+    }
+
+    fn serve_with(compression: Compression) -> Server {
+        let mut app = Server::new();
+        app.around(compression.into_middleware());
+        app.get("/text", |_, res| {
+            res.header("Content-Type: text/plain; charset=utf-8");
+            res.send(text())
+        });
+        app.get("/short", |_, res| {
+            res.header("Content-Type: text/plain");
+            res.send("short")
+        });
+        app.get("/png", |_, res| {
+            res.header("Content-Type: image/png");
+            res.send(text())
+        });
+        app.get("/encoded", |_, res| {
+            res.header("Content-Type: text/plain");
+            res.header("Content-Encoding: gzip");
+            res.send(text())
+        });
+        app
+    }
+
+    fn get(app: &mut Server, path: &str, accept_encoding: &str) -> Sent {
+        send(
+            app,
+            &format!("GET {path} HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n"),
+        )
+    }
+
+    fn decode(res: &Sent) -> String {
+        let body = &res.body[..];
+        let mut decoded = String::new();
+        match res.header("content-encoding") {
+            Some("br") => brotli::Decompressor::new(body, 4096).read_to_string(&mut decoded),
+            Some("gzip") => GzDecoder::new(body).read_to_string(&mut decoded),
+            Some("deflate") => ZlibDecoder::new(body).read_to_string(&mut decoded),
+            other => panic!("unexpected encoding {:?}", other),
+        }
+        .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn compresses_with_the_negotiated_encoding() {
+        let mut app = serve_with(Compression::new());
+        for (accept_encoding, encoding) in
+            [("br, gzip", "br"), ("gzip", "gzip"), ("deflate", "deflate")]
+        {
+            let res = get(&mut app, "/text", accept_encoding);
+            assert_eq!(res.status, 200);
+            assert_eq!(res.header("content-encoding"), Some(encoding));
+            assert_eq!(res.header("vary"), Some("Accept-Encoding"));
+            let len = res.header("content-length").unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), res.body.len());
+            assert!(res.body.len() < text().len());
+            assert_eq!(decode(&res), text());
+        }
+    }
+
+    #[test]
+    fn compresses_on_a_small_coroutine_stack() {
+        // a connection coroutine has may's default stack
+        let res = may::go!(|| get(&mut serve_with(Compression::new()), "/text", "br"))
+            .join()
+            .unwrap();
+        assert_eq!(res.header("content-encoding"), Some("br"));
+        assert_eq!(decode(&res), text());
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let mut app = serve_with(Compression::new().min_size(64));
+        let res = get(&mut app, "/short", "gzip");
+        assert_eq!(res.header("content-encoding"), None);
+        assert_eq!(res.header("vary"), None);
+        assert_eq!(res.body(), "short");
+
+        let res = get(&mut app, "/png", "gzip");
+        assert_eq!(res.header("content-encoding"), None);
+        assert_eq!(res.body(), text());
+
+        let res = get(&mut app, "/encoded", "br");
+        assert_eq!(res.header("content-encoding"), Some("gzip"));
+        assert_eq!(res.body(), text());
+
+        // the body could have been compressed, so caches must keep apart
+        let res = get(&mut app, "/text", "identity");
+        assert_eq!(res.header("content-encoding"), None);
+        assert_eq!(res.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(
+            res.header("content-length"),
+            Some(&*text().len().to_string())
+        );
+        assert_eq!(res.body(), text());
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+    }
+}
@@ -0,0 +1,318 @@
+use std::io;
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::server::Next;
+
+/// Cross-origin resource sharing, installed with `Server::around`:
+///
+/// ```
+/// # use server_nano::{Cors, Server};
+/// let mut app = Server::new();
+/// app.around(
+///     Cors::new()
+///         .allow_origin("https://*.example.com")
+///         .allow_credentials(true)
+///         .into_middleware(),
+/// );
+/// ```
+///
+/// Preflight `OPTIONS` requests from an allowed origin are answered without
+/// reaching the routes; other requests from an allowed origin get the
+/// `Access-Control-*` headers added to their response.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    // lowercase, `*` alone allows any origin and `*` inside matches any text
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            origins: Vec::new(),
+            methods: ["GET", "HEAD", "PUT", "PATCH", "POST", "DELETE"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors::default()
+    }
+
+    /// Allows an origin such as `https://app.example.com`, a pattern such as
+    /// `https://*.example.com`, or `*` for any origin. `*` cannot be combined
+    /// with `allow_credentials(true)`.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.to_ascii_lowercase());
+        self
+    }
+
+    /// Replaces the methods allowed in preflight requests, which default to
+    /// `GET, HEAD, PUT, PATCH, POST, DELETE`.
+    pub fn allow_methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|method| method.to_string()).collect();
+        self
+    }
+
+    /// The request headers allowed in preflight requests. When none are set,
+    /// the headers the browser asks for are allowed.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// The response headers that scripts may read.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Lets browsers send cookies and authorization headers. The allowed
+    /// origins must then be listed, not `*`.
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long, in seconds, browsers may cache a preflight response.
+    pub fn max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If credentials are allowed for any origin, which would let every site
+    /// make authenticated requests.
+    pub fn into_middleware(
+        self,
+    ) -> impl Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static {
+        assert!(
+            !(self.credentials && self.origins.iter().any(|origin| origin == "*")),
+            "Cors: allow_origin(\"*\") cannot be combined with allow_credentials(true)"
+        );
+        move |req, res, next| self.handle(req, res, next)
+    }
+
+    fn handle(&self, req: &mut Request, res: &mut Response, next: Next) -> io::Result<()> {
+        // other responses depend on the origin and must say so to caches
+        let any_origin = self.origins.iter().any(|origin| origin == "*");
+        if !any_origin {
+            res.header("Vary: Origin");
+        }
+
+        let origin = match req.header("origin") {
+            Some(origin) if self.allows(origin) => origin.to_string(),
+            _ => return next.run(req, res),
+        };
+        if any_origin {
+            res.header("Access-Control-Allow-Origin: *");
+        } else {
+            res.header(format!("Access-Control-Allow-Origin: {}", origin));
+        }
+        if self.credentials {
+            res.header("Access-Control-Allow-Credentials: true");
+        }
+
+        let preflight =
+            req.method() == "OPTIONS" && req.header("access-control-request-method").is_some();
+        if !preflight {
+            if !self.exposed_headers.is_empty() {
+                res.header(format!(
+                    "Access-Control-Expose-Headers: {}",
+                    self.exposed_headers.join(", ")
+                ));
+            }
+            return next.run(req, res);
+        }
+
+        res.status_code(204, "No Content");
+        res.header(format!(
+            "Access-Control-Allow-Methods: {}",
+            self.methods.join(", ")
+        ));
+        let headers = match (
+            self.headers.is_empty(),
+            req.header("access-control-request-headers"),
+        ) {
+            (false, _) => Some(self.headers.join(", ")),
+            (true, Some(requested)) => Some(requested.to_string()),
+            (true, None) => None,
+        };
+        if let Some(headers) = headers {
+            res.header(format!("Access-Control-Allow-Headers: {}", headers));
+        }
+        if let Some(max_age) = self.max_age {
+            res.header(format!("Access-Control-Max-Age: {}", max_age));
+        }
+        Ok(())
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.origins
+            .iter()
+            .any(|pattern| origin_matches(pattern, &origin))
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once('*') {
+        Some(("", "")) => true,
+        Some((prefix, suffix)) => {
+            origin.len() > prefix.len() + suffix.len()
+                && origin.starts_with(prefix)
+                && origin.ends_with(suffix)
+        }
+        None => pattern == origin,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::server::Server;
+    use crate::server::testing::send;
+
+    fn serve_with(cors: Cors) -> Server {
+        let mut app = Server::new();
+        app.around(cors.into_middleware());
+        app.get("/r", |_, res| res.send("r"));
+        app
+    }
+
+    #[test]
+    fn answers_preflight_requests() {
+        let mut app = serve_with(
+            Cors::new()
+                .allow_origin("https://*.example.com")
+                .allow_credentials(true)
+                .allow_headers(&["Content-Type"])
+                .max_age(600),
+        );
+        let res = send(
+            &mut app,
+            "OPTIONS /r HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+             Access-Control-Request-Method: PUT\r\n\
+             Access-Control-Request-Headers: X-Custom\r\n\r\n",
+        );
+        assert_eq!(res.status, 204);
+        assert_eq!(res.header("vary"), Some("Origin"));
+        assert_eq!(
+            res.header("access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(res.header("access-control-allow-credentials"), Some("true"));
+        assert_eq!(
+            res.header("access-control-allow-methods"),
+            Some("GET, HEAD, PUT, PATCH, POST, DELETE")
+        );
+        assert_eq!(
+            res.header("access-control-allow-headers"),
+            Some("Content-Type")
+        );
+        assert_eq!(res.header("access-control-max-age"), Some("600"));
+        assert_eq!(res.header("content-length"), None);
+
+        // without configured headers the requested ones are allowed
+        let mut app = serve_with(Cors::new().allow_origin("*"));
+        let res = send(
+            &mut app,
+            "OPTIONS /r HTTP/1.1\r\nOrigin: https://a.org\r\n\
+             Access-Control-Request-Method: PUT\r\n\
+             Access-Control-Request-Headers: X-Custom\r\n\r\n",
+        );
+        assert_eq!(res.status, 204);
+        assert_eq!(res.header("access-control-allow-origin"), Some("*"));
+        assert_eq!(res.header("access-control-allow-headers"), Some("X-Custom"));
+        assert_eq!(res.header("access-control-allow-credentials"), None);
+        assert_eq!(res.header("vary"), None);
+    }
+
+    #[test]
+    fn adds_headers_for_allowed_origins_only() {
+        let mut app = serve_with(
+            Cors::new()
+                .allow_origin("https://app.example.com")
+                .expose_headers(&["X-Total", "X-Page"]),
+        );
+
+        let res = send(
+            &mut app,
+            "GET /r HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n",
+        );
+        assert_eq!(res.body(), "r");
+        assert_eq!(res.header("vary"), Some("Origin"));
+        assert_eq!(
+            res.header("access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            res.header("access-control-expose-headers"),
+            Some("X-Total, X-Page")
+        );
+        assert_eq!(res.header("access-control-allow-credentials"), None);
+
+        // other origins reach the routes without any CORS headers, but
+        // caches still need to know the response depends on the origin
+        let res = send(
+            &mut app,
+            "GET /r HTTP/1.1\r\nOrigin: https://evil.org\r\n\r\n",
+        );
+        assert_eq!(res.body(), "r");
+        assert_eq!(res.header("vary"), Some("Origin"));
+        assert!(!res
+            .headers
+            .iter()
+            .any(|(name, _)| name.to_ascii_lowercase().starts_with("access-control-")));
+
+        let res = send(
+            &mut app,
+            "OPTIONS /r HTTP/1.1\r\nOrigin: https://evil.org\r\n\
+             Access-Control-Request-Method: PUT\r\n\r\n",
+        );
+        assert_eq!(res.header("allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(res.header("access-control-allow-methods"), None);
+    }
+
+    #[test]
+    fn matches_origin_patterns() {
+        assert!(origin_matches("*", "https://example.com"));
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://app.eu.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com.evil.org"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "allow_credentials")]
+    fn rejects_credentials_for_any_origin() {
+        let _ = Cors::new()
+            .allow_origin("*")
+            .allow_credentials(true)
+            .into_middleware();
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::server::Next;
+
+// buckets are swept once this many clients are tracked, at most once per
+// interval since a sweep walks every bucket under the lock
+const SWEEP_THRESHOLD: usize = 10_000;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// once this many clients are tracked, the fullest buckets are evicted in
+// batches so that a pass over every bucket is rare
+const MAX_BUCKETS: usize = 100_000;
+const EVICT_BATCH: usize = MAX_BUCKETS / 10;
+
+type KeyFn = dyn Fn(&Request) -> Option<String> + Send + Sync;
+
+/// Limits each client to `limit` requests per `period` with a token bucket,
+/// installed with `Server::around` or `Route::with`:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use server_nano::{RateLimit, Server};
+/// let mut app = Server::new();
+/// app.around(RateLimit::new(100, Duration::from_secs(60)).into_middleware());
+/// ```
+///
+/// Clients are told their quota with the `RateLimit-*` headers, and rejected
+/// with a 429 and `Retry-After` once it is used up. Clones share their
+/// buckets, so one limiter can guard several routes. At most 100,000 clients
+/// are tracked; beyond that the clients closest to a full quota, who lose
+/// the least by starting over, are forgotten to make room.
+#[derive(Clone)]
+pub struct RateLimit {
+    limit: u32,
+    period: Duration,
+    key: Arc<KeyFn>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, limit: u32, period: Duration) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = f64::from(limit) / period.as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(limit));
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, now: Instant, limit: u32, period: Duration) -> Result<(), Duration> {
+        self.refill(now, limit, period);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.time_to(1.0, limit, period))
+        }
+    }
+
+    fn time_to(&self, tokens: f64, limit: u32, period: Duration) -> Duration {
+        let missing = (tokens - self.tokens).max(0.0);
+        period.mul_f64(missing / f64::from(limit))
+    }
+}
+
+impl Buckets {
+    /// The bucket of `key`, which starts out full.
+    fn get(&mut self, key: String, now: Instant, limit: u32, period: Duration) -> &mut Bucket {
+        let since_sweep = now.saturating_duration_since(self.swept);
+        if self.map.len() >= SWEEP_THRESHOLD && since_sweep >= SWEEP_INTERVAL {
+            // full buckets hold no state worth keeping
+            self.map.retain(|_, bucket| {
+                bucket.refill(now, limit, period);
+                bucket.tokens < f64::from(limit)
+            });
+            self.swept = now;
+        }
+        if self.map.len() >= MAX_BUCKETS && !self.map.contains_key(&key) {
+            self.evict(now, limit, period);
+        }
+        self.map.entry(key).or_insert(Bucket {
+            tokens: f64::from(limit),
+            updated: now,
+        })
+    }
+
+    /// Drops the `EVICT_BATCH` fullest buckets, the least recently updated
+    /// first among equally full ones.
+    fn evict(&mut self, now: Instant, limit: u32, period: Duration) {
+        let mut candidates = self
+            .map
+            .iter_mut()
+            .map(|(key, bucket)| {
+                let updated = bucket.updated;
+                bucket.refill(now, limit, period);
+                (bucket.tokens, updated, key)
+            })
+            .collect::<Vec<_>>();
+        let batch = EVICT_BATCH.min(candidates.len());
+        if batch < candidates.len() {
+            candidates
+                .select_nth_unstable_by(batch, |a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        }
+        let evicted = candidates[..batch]
+            .iter()
+            .map(|(_, _, key)| (*key).clone())
+            .collect::<Vec<_>>();
+        for key in evicted {
+            self.map.remove(&key);
+        }
+    }
+}
+
+impl RateLimit {
+    /// Allows bursts of up to `limit` requests, refilled evenly over `period`.
+    /// Clients are keyed by IP address.
+    pub fn new(limit: u32, period: Duration) -> Self {
+        RateLimit {
+            limit: limit.max(1),
+            period,
+            key: Arc::new(|req: &Request| req.peer_addr().map(|addr| addr.ip().to_string())),
+            buckets: Arc::new(Mutex::new(Buckets {
+                map: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Keys clients by something other than their IP address, e.g. an API
+    /// key header. Requests without a key are not limited.
+    pub fn key<F>(mut self, key: F) -> Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Arc::new(key);
+        self
+    }
+
+    pub fn into_middleware(
+        self,
+    ) -> impl Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static {
+        move |req, res, next| self.handle(req, res, next)
+    }
+
+    fn handle(&self, req: &mut Request, res: &mut Response, next: Next) -> io::Result<()> {
+        let key = match (self.key)(req) {
+            Some(key) => key,
+            None => return next.run(req, res),
+        };
+
+        let now = Instant::now();
+        let (taken, remaining, reset) = {
+            let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+            let bucket = buckets.get(key, now, self.limit, self.period);
+            let taken = bucket.take(now, self.limit, self.period);
+            let reset = bucket.time_to(f64::from(self.limit), self.limit, self.period);
+            (taken, bucket.tokens as u32, reset)
+        };
+
+        res.header(format!("RateLimit-Limit: {}", self.limit));
+        res.header(format!("RateLimit-Remaining: {}", remaining));
+        res.header(format!("RateLimit-Reset: {}", ceil_secs(reset)));
+        match taken {
+            Ok(()) => next.run(req, res),
+            Err(wait) => {
+                res.status_code(429, "Too Many Requests");
+                res.header(format!("Retry-After: {}", ceil_secs(wait)));
+                Ok(())
+            }
+        }
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_tokens_over_time() {
+        let period = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2.0,
+            updated: start,
+        };
+
+        assert!(bucket.take(start, 2, period).is_ok());
+        assert!(bucket.take(start, 2, period).is_ok());
+        assert_eq!(bucket.take(start, 2, period), Err(Duration::from_secs(5)));
+
+        let later = start + Duration::from_secs(5);
+        assert!(bucket.take(later, 2, period).is_ok());
+        assert!(bucket.take(later, 2, period).is_err());
+
+        let much_later = later + Duration::from_secs(60);
+        bucket.refill(much_later, 2, period);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn sweeps_at_most_once_per_interval() {
+        let (limit, period) = (2, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut buckets = Buckets {
+            map: HashMap::new(),
+            swept: start,
+        };
+        for i in 0..SWEEP_THRESHOLD {
+            buckets.get(i.to_string(), start, limit, period);
+        }
+
+        // every bucket is still full, but it is too soon to sweep again
+        buckets.get("a".to_string(), start, limit, period);
+        assert_eq!(buckets.map.len(), SWEEP_THRESHOLD + 1);
+
+        let later = start + SWEEP_INTERVAL;
+        buckets
+            .get("b".to_string(), later, limit, period)
+            .take(later, limit, period)
+            .unwrap();
+        assert_eq!(buckets.map.len(), 1);
+    }
+
+    #[test]
+    fn evicts_the_fullest_buckets_when_full() {
+        let (limit, period) = (2, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut buckets = Buckets {
+            map: HashMap::new(),
+            swept: start,
+        };
+        // every client but the first few has one token left, and the
+        // earlier ones were updated longer ago
+        for i in 0..MAX_BUCKETS {
+            let now = start + Duration::from_nanos(i as u64);
+            let bucket = buckets.get(i.to_string(), now, limit, period);
+            bucket.take(now, limit, period).unwrap();
+            if i < 10 {
+                bucket.take(now, limit, period).unwrap();
+            }
+        }
+
+        let now = start + Duration::from_millis(1);
+        let bucket = buckets.get("new".to_string(), now, limit, period);
+        assert_eq!(bucket.tokens, 2.0);
+        assert_eq!(buckets.map.len(), MAX_BUCKETS - EVICT_BATCH + 1);
+        // drained clients are kept, and so still limited
+        for i in 0..10 {
+            let bucket = buckets.map.get_mut(&i.to_string()).unwrap();
+            assert!(bucket.take(now, limit, period).is_err());
+        }
+        assert!(!buckets.map.contains_key("10"));
+        assert!(!buckets.map.contains_key(&(EVICT_BATCH + 9).to_string()));
+        assert!(buckets.map.contains_key(&(EVICT_BATCH + 10).to_string()));
+
+        // there is room again until the next batch
+        for i in 0..EVICT_BATCH - 1 {
+            buckets.get(format!("later-{}", i), now, limit, period);
+        }
+        assert_eq!(buckets.map.len(), MAX_BUCKETS);
+    }
+}
@@ -0,0 +1,285 @@
+use serde::de::value::StrDeserializer;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+
+use crate::errors::errors::QueryError;
+
+/// Deserializes query string pairs into `T`. A key maps to a single value,
+/// or to every value of the key when the field is a sequence.
+pub(crate) fn from_pairs<T: de::DeserializeOwned>(
+    pairs: &[(String, String)],
+) -> Result<T, QueryError> {
+    // group repeated keys, keeping the order of first appearance
+    let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (key, value) in pairs {
+        match fields.iter_mut().find(|(k, _)| k == key) {
+            Some((_, values)) => values.push(value),
+            None => fields.push((key, vec![value])),
+        }
+    }
+
+    T::deserialize(QueryDeserializer {
+        fields: fields.into_iter(),
+        key: None,
+        value: None,
+    })
+}
+
+struct QueryDeserializer<'a> {
+    fields: std::vec::IntoIter<(&'a str, Vec<&'a str>)>,
+    key: Option<&'a str>,
+    value: Option<Vec<&'a str>>,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for QueryDeserializer<'a> {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_map(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a> MapAccess<'de> for QueryDeserializer<'a> {
+    type Error = QueryError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, QueryError> {
+        match self.fields.next() {
+            Some((key, values)) => {
+                self.key = Some(key);
+                self.value = Some(values);
+                let key: StrDeserializer<QueryError> = key.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, QueryError> {
+        let key = self.key.unwrap_or_default();
+        let values = self.value.take().unwrap_or_default();
+        seed.deserialize(ValuesDeserializer { values })
+            .map_err(|e| e.with_field(key))
+    }
+}
+
+/// Every value given for one key.
+struct ValuesDeserializer<'a> {
+    values: Vec<&'a str>,
+}
+
+impl<'a> ValuesDeserializer<'a> {
+    fn first(&self) -> ValueDeserializer<'a> {
+        ValueDeserializer(self.values.first().copied().unwrap_or_default())
+    }
+}
+
+macro_rules! forward_to_first {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+                self.first().$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValuesDeserializer<'a> {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        if self.values.len() > 1 {
+            self.deserialize_seq(visitor)
+        } else {
+            self.first().deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_seq(ValuesSeq(self.values.into_iter()))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        self.first().deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_first! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_i128 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_u128 deserialize_f32 deserialize_f64 deserialize_char deserialize_str
+        deserialize_string deserialize_unit
+    }
+
+    serde::forward_to_deserialize_any! {
+        bytes byte_buf unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct ValuesSeq<'a>(std::vec::IntoIter<&'a str>);
+
+impl<'de, 'a> SeqAccess<'de> for ValuesSeq<'a> {
+    type Error = QueryError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, QueryError> {
+        match self.0.next() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A single query value, parsed according to the type being deserialized.
+struct ValueDeserializer<'a>(&'a str);
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(e) => Err(de::Error::custom(format_args!("invalid value {:?}: {}", self.0, e))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_str(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        let variant: StrDeserializer<QueryError> = self.0.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Search {
+        q: String,
+        page: u32,
+        #[serde(default)]
+        exact: bool,
+        limit: Option<u8>,
+        tag: Vec<String>,
+    }
+
+    fn pairs(query: &[(&str, &str)]) -> Vec<(String, String)> {
+        query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn deserializes_typed_fields() {
+        let search: Search = from_pairs(&pairs(&[
+            ("q", "rust"),
+            ("tag", "a"),
+            ("page", "2"),
+            ("tag", "b"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            search,
+            Search {
+                q: "rust".to_string(),
+                page: 2,
+                exact: false,
+                limit: None,
+                tag: vec!["a".to_string(), "b".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn reports_the_offending_field() {
+        let error = from_pairs::<Search>(&pairs(&[("q", "rust"), ("page", "two"), ("tag", "a")]))
+            .unwrap_err();
+        assert_eq!(error.field(), Some("page"));
+
+        let error = from_pairs::<Search>(&pairs(&[("page", "1"), ("tag", "a")])).unwrap_err();
+        assert_eq!(error.field(), Some("q"));
+    }
+}
@@ -1,23 +1,52 @@
+use std::any::Any;
 use std::collections::HashMap;
 use std::fmt;
 use std::io::{self, BufRead, Read};
 use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 
 pub(crate) const MAX_HEADERS: usize = 16;
 
 use bytes::{Buf, BufMut, BytesMut};
 use may::net::TcpStream;
+use serde::de::DeserializeOwned;
 
 use crate::errors::errors::RequestError;
+use crate::request::query;
 
 #[derive()]
 pub struct Request<'buf, 'header, 'stream> {
     pub parameters: HashMap<String, String>,
-    pub url_parameters: HashMap<String, String>,
+    // query string pairs in order, repeated keys included
+    pub url_parameters: Vec<(String, String)>,
+    // who the request was authenticated as, set by an auth middleware
+    pub(crate) principal: Option<Arc<dyn Any + Send + Sync>>,
     pub(crate) req: RawRequest<'buf, 'header, 'stream>,
 }
 
 impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
+    pub(crate) fn new(req: RawRequest<'buf, 'header, 'stream>) -> Self {
+        Request {
+            parameters: HashMap::new(),
+            url_parameters: Vec::new(),
+            principal: None,
+            req,
+        }
+    }
+
+    /// A request borrowing this one, for a handler that takes its request
+    /// by value while the around middleware keep theirs for a retry.
+    pub(crate) fn reborrow(&mut self) -> Request<'_, '_, '_> {
+        Request {
+            parameters: self.parameters.clone(),
+            url_parameters: self.url_parameters.clone(),
+            principal: self.principal.clone(),
+            req: self.req.reborrow(),
+        }
+    }
+
     pub fn method(&self) -> &str {
         self.req.method()
     }
@@ -34,9 +63,31 @@ impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
         self.req.headers()
     }
 
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.req.header(name)
+    }
+
+    pub fn request_id(&self) -> &str {
+        self.req.request_id()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.req.peer_addr()
+    }
+
+    /// The principal attached by an authentication middleware, if it is a
+    /// `T`. `BasicAuth` attaches the user name as a `String`.
+    pub fn principal<T: Any>(&self) -> Option<&T> {
+        self.principal.as_ref()?.downcast_ref()
+    }
+
+    pub fn set_principal<T: Any + Send + Sync>(&mut self, principal: T) {
+        self.principal = Some(Arc::new(principal));
+    }
+
     pub fn json_body(self) -> Result<serde_json::Value, RequestError> {
         let value: serde_json::Value = serde_json::from_reader(self.body())?;
-        Ok(value).map_err(|e| RequestError::JsonError(e))
+        Ok(value)
     }
 
     pub fn body(self) -> BodyReader<'buf, 'stream> {
@@ -47,15 +98,47 @@ impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
         self.parameters.get(name).map(|s| s.as_str())
     }
 
+    /// Parses a route parameter, e.g. `req.param::<u64>("id")`.
+    pub fn param<T>(&self, name: &str) -> Result<T, RequestError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let value = self
+            .parameter(name)
+            .ok_or_else(|| RequestError::MissingParameter(name.to_string()))?;
+        value
+            .parse()
+            .map_err(|e: T::Err| RequestError::InvalidParameter(name.to_string(), e.to_string()))
+    }
+
     pub fn url_parameter(&self, name: &str) -> Option<&str> {
-        self.url_parameters.get(name).map(|s| s.as_str())
+        self.url_parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Deserializes the whole query string, e.g. into a struct deriving
+    /// `Deserialize`. Repeated keys fill `Vec` fields.
+    pub fn query<T: DeserializeOwned>(&self) -> Result<T, RequestError> {
+        Ok(query::from_pairs(&self.url_parameters)?)
+    }
+
+    /// Every value of a repeated query parameter, e.g. `?tag=a&tag=b`.
+    pub fn url_parameters_all(&self, name: &str) -> Vec<&str> {
+        self.url_parameters
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
     }
 
     pub fn keep_alive(&self) -> bool {
-        return self.headers().iter().any(|header| {
+        self.headers().iter().any(|header| {
             header.name.eq_ignore_ascii_case("connection")
                 && std::str::from_utf8(header.value).ok() == Some("keep-alive")
-        });
+        })
     }
 }
 
@@ -117,22 +200,37 @@ impl<'buf, 'stream> fmt::Debug for BodyReader<'buf, 'stream> {
     }
 }
 pub struct RawRequest<'buf, 'header, 'stream> {
-    req: httparse::Request<'header, 'buf>,
+    method: &'buf str,
+    path: &'buf str,
+    version: u8,
+    headers: &'header [httparse::Header<'buf>],
     req_buf: &'buf mut BytesMut,
     stream: &'stream mut TcpStream,
+    pub(crate) request_id: String,
 }
 
 impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
+    /// The ID the server assigned to this request, also sent back in the
+    /// `X-Request-Id` response header.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// The address of the client, or of the proxy in front of the server.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+
     pub fn method(&self) -> &str {
-        self.req.method.unwrap()
+        self.method
     }
 
     pub fn path(&self) -> &str {
-        self.req.path.unwrap()
+        self.path
     }
 
     pub fn version(&self) -> u8 {
-        self.req.version.unwrap()
+        self.version
     }
 
     pub fn json_body(&self) -> Result<serde_json::Value, RequestError> {
@@ -143,7 +241,15 @@ impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
     }
 
     pub fn headers(&self) -> &[httparse::Header<'_>] {
-        self.req.headers
+        self.headers
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
     }
 
     pub fn body(self) -> BodyReader<'buf, 'stream> {
@@ -157,7 +263,7 @@ impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
 
     fn content_length(&self) -> usize {
         let mut len = usize::MAX;
-        for header in self.req.headers.iter() {
+        for header in self.headers.iter() {
             if header.name.eq_ignore_ascii_case("content-length") {
                 len = std::str::from_utf8(header.value).unwrap().parse().unwrap();
                 break;
@@ -165,6 +271,18 @@ impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
         }
         len
     }
+
+    fn reborrow(&mut self) -> RawRequest<'_, '_, '_> {
+        RawRequest {
+            method: self.method,
+            path: self.path,
+            version: self.version,
+            headers: self.headers,
+            req_buf: &mut *self.req_buf,
+            stream: &mut *self.stream,
+            request_id: self.request_id.clone(),
+        }
+    }
 }
 
 impl<'buf, 'header, 'stream> fmt::Debug for RawRequest<'buf, 'header, 'stream> {
@@ -187,7 +305,7 @@ pub fn decode<'header, 'buf, 'stream>(
         Err(e) => {
             eprintln!("failed to parse http request: {e:?}");
             let msg = format!("failed to parse http request: {e:?}");
-            return Err(io::Error::new(io::ErrorKind::Other, msg));
+            return Err(io::Error::other(msg));
         }
     };
 
@@ -197,9 +315,14 @@ pub fn decode<'header, 'buf, 'stream>(
     };
     req_buf.advance(len);
 
+    // a complete request has all of these
     Ok(Some(RawRequest {
-        req,
+        method: req.method.unwrap(),
+        path: req.path.unwrap(),
+        version: req.version.unwrap(),
+        headers: req.headers,
         req_buf,
         stream,
+        request_id: String::new(),
     }))
 }
@@ -0,0 +1,84 @@
+use std::borrow::Cow;
+
+use crate::errors::errors::RequestError;
+
+/// Decodes `%XX` escapes as described in RFC 3986. With `plus_as_space`,
+/// `+` is decoded as a space as in `application/x-www-form-urlencoded`.
+/// Malformed escapes are kept verbatim; the decoded bytes must be UTF-8.
+pub(crate) fn percent_decode(
+    input: &str,
+    plus_as_space: bool,
+) -> Result<Cow<'_, str>, RequestError> {
+    let escaped = input.contains('%') || (plus_as_space && input.contains('+'));
+    if !escaped {
+        return Ok(Cow::Borrowed(input));
+    }
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match (hex_value(bytes.get(i + 1)), hex_value(bytes.get(i + 2))) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    match String::from_utf8(decoded) {
+        Ok(s) => Ok(Cow::Owned(s)),
+        Err(e) => Err(RequestError::Utf8Error(e.utf8_error())),
+    }
+}
+
+/// Escapes everything but the characters allowed in a path segment.
+pub(crate) fn percent_encode(input: &str) -> Cow<'_, str> {
+    let allowed = |b: u8| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b);
+    if input.bytes().all(allowed) {
+        return Cow::Borrowed(input);
+    }
+
+    let mut encoded = String::with_capacity(input.len() + 8);
+    for b in input.bytes() {
+        if allowed(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+fn hex_value(byte: Option<&u8>) -> Option<u8> {
+    match byte? {
+        b @ b'0'..=b'9' => Some(b - b'0'),
+        b @ b'a'..=b'f' => Some(b - b'a' + 10),
+        b @ b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Splits a query string into its key/value pairs, keeping repeated keys
+/// in the order they appear.
+pub(crate) fn parse_query(query_string: &str) -> Result<Vec<(String, String)>, RequestError> {
+    query_string
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let (key, value) = s.split_once('=').unwrap_or((s, ""));
+            Ok((
+                percent_decode(key, true)?.into_owned(),
+                percent_decode(value, true)?.into_owned(),
+            ))
+        })
+        .collect()
+}
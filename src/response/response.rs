@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::io;
 
 use crate::request::request::MAX_HEADERS;
@@ -6,10 +7,13 @@ use bytes::{BufMut, BytesMut};
 use serde;
 
 pub struct Response<'a> {
-    headers: [&'static str; MAX_HEADERS],
-    headers_len: usize,
+    headers: Vec<Cow<'static, str>>,
     status_message: StatusMessage,
+    status_set: bool,
     body: Body,
+    // HEAD responses keep Content-Length but send no body
+    skip_body: bool,
+    request_id: Option<String>,
     res_buf: &'a mut BytesMut,
 }
 
@@ -26,13 +30,13 @@ struct StatusMessage {
 }
 
 impl<'a> Response<'a> {
-    pub(crate) fn new(res_buf: &'a mut BytesMut) -> Response {
-        let headers: [&'static str; 16] = [""; 16];
-
+    pub(crate) fn new(res_buf: &'a mut BytesMut) -> Response<'a> {
         Response {
-            headers,
-            headers_len: 0,
+            headers: Vec::with_capacity(MAX_HEADERS),
             body: Body::Dummy,
+            status_set: false,
+            skip_body: false,
+            request_id: None,
             status_message: StatusMessage {
                 code: 200,
                 msg: "Ok",
@@ -44,16 +48,54 @@ impl<'a> Response<'a> {
     #[inline]
     pub fn status_code(&mut self, code: usize, msg: &'static str) -> &mut Self {
         self.status_message = StatusMessage { code, msg };
+        self.status_set = true;
         self
     }
 
     #[inline]
-    pub fn header(&mut self, header: &'static str) -> &mut Self {
-        self.headers[self.headers_len] = header;
-        self.headers_len += 1;
+    pub fn status(&self) -> usize {
+        self.status_message.code
+    }
+
+    #[inline]
+    pub fn header(&mut self, header: impl Into<Cow<'static, str>>) -> &mut Self {
+        self.headers.push(header.into());
         self
     }
 
+    /// The value of the first header called `name`, ignoring case.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|header| {
+            let (key, value) = header.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    /// Whether a status code or a body has been set.
+    #[inline]
+    pub(crate) fn is_written(&self) -> bool {
+        self.status_set || !matches!(self.body, Body::Dummy) || !self.res_buf.is_empty()
+    }
+
+    /// The ID of the request this response answers.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub(crate) fn take_request_id(&mut self) -> Option<String> {
+        self.request_id.take()
+    }
+
+    pub(crate) fn set_request_id(&mut self, request_id: &str) {
+        self.header(format!("X-Request-Id: {}", request_id));
+        self.request_id = Some(request_id.to_string());
+    }
+
+    #[inline]
+    pub(crate) fn skip_body(&mut self) {
+        self.skip_body = true;
+    }
+
     #[inline]
     pub fn body(&mut self, s: &'static str) {
         self.body = Body::StaticStr(s);
@@ -85,13 +127,12 @@ impl<'a> Response<'a> {
 
     pub fn send<S: AsRef<str>>(&mut self, content: S) -> io::Result<()> {
         match content.as_ref() {
-            s if s.len() == 0 => self.body = Body::Dummy,
+            "" => self.body = Body::Dummy,
             s => self.body = Body::Str(s.to_owned()),
         }
         Ok(())
     }
 
-
     #[inline]
     pub fn body_mut(&mut self) -> &mut BytesMut {
         match self.body {
@@ -111,6 +152,21 @@ impl<'a> Response<'a> {
         }
         self.res_buf
     }
+    /// The number of body bytes that will be sent.
+    pub(crate) fn sent_len(&self) -> usize {
+        if self.skip_body || !self.has_body() {
+            0
+        } else {
+            self.body_len()
+        }
+    }
+
+    // 1xx, 204 and 304 responses never have a body, nor a Content-Length
+    #[inline]
+    fn has_body(&self) -> bool {
+        !matches!(self.status_message.code, 100..=199 | 204 | 304)
+    }
+
     #[inline]
     fn body_len(&self) -> usize {
         match self.body {
@@ -122,7 +178,7 @@ impl<'a> Response<'a> {
     }
 
     #[inline]
-    fn get_body(&mut self) -> &[u8] {
+    pub(crate) fn get_body(&mut self) -> &[u8] {
         match self.body {
             Body::Dummy => self.res_buf.as_ref(),
             Body::StaticStr(s) => s.as_bytes(),
@@ -150,23 +206,33 @@ pub(crate) fn encode(mut rsp: Response, buf: &mut BytesMut) {
         buf.extend_from_slice(b"\r\nServer: M\r\nDate: ");
     }
     crate::response::date::append_date(buf);
-    buf.extend_from_slice(b"\r\nContent-Length: ");
-    let mut length = itoa::Buffer::new();
-    buf.extend_from_slice(length.format(rsp.body_len()).as_bytes());
+    let has_body = rsp.has_body();
+    if has_body {
+        buf.extend_from_slice(b"\r\nContent-Length: ");
+        let mut length = itoa::Buffer::new();
+        buf.extend_from_slice(length.format(rsp.body_len()).as_bytes());
+    }
 
-    // SAFETY: we already have bound check when insert headers
-    let headers = unsafe { rsp.headers.get_unchecked(..rsp.headers_len) };
-    for h in headers {
+    for h in &rsp.headers {
         buf.extend_from_slice(b"\r\n");
         buf.extend_from_slice(h.as_bytes());
     }
 
     buf.extend_from_slice(b"\r\n\r\n");
-    buf.extend_from_slice(rsp.get_body());
+    if has_body && !rsp.skip_body {
+        buf.extend_from_slice(rsp.get_body());
+    }
 }
 
-pub(crate) fn encode_error(e: io::Error, buf: &mut BytesMut) {
-    error!("error in service: err = {:?}", e);
+/// Encodes a 500 response for `e` and returns the length of its body.
+pub(crate) fn encode_error(e: io::Error, request_id: Option<&str>, buf: &mut BytesMut) -> usize {
+    match request_id {
+        Some(request_id) => error!(
+            "error in service: request_id = {}, err = {:?}",
+            request_id, e
+        ),
+        None => error!("error in service: err = {:?}", e),
+    }
     let msg_string = e.to_string();
     let msg = msg_string.as_bytes();
 
@@ -175,7 +241,12 @@ pub(crate) fn encode_error(e: io::Error, buf: &mut BytesMut) {
     buf.extend_from_slice(b"\r\nContent-Length: ");
     let mut length = itoa::Buffer::new();
     buf.extend_from_slice(length.format(msg.len()).as_bytes());
+    if let Some(request_id) = request_id {
+        buf.extend_from_slice(b"\r\nX-Request-Id: ");
+        buf.extend_from_slice(request_id.as_bytes());
+    }
 
     buf.extend_from_slice(b"\r\n\r\n");
     buf.extend_from_slice(msg);
+    msg.len()
 }
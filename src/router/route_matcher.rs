@@ -1,167 +1,1053 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::hash::{Hash, Hasher};
 use std::io;
 use std::sync::Arc;
 
+use regex::Regex;
+
+use crate::errors::errors::{RequestError, RouteError};
 use crate::request::request::Request;
+use crate::request::url::{parse_query, percent_decode, percent_encode};
+use crate::server::server::{AroundMiddleware, Middleware, Next};
 use crate::Response;
 
 pub type RouteHandler =
     Box<dyn Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static>;
 
-#[derive(Clone)]
+/// Routes are stored in a prefix tree keyed by path segment, so a lookup
+/// walks at most one node per segment of the request path instead of
+/// testing every registered route.
+///
+/// When several routes could match a path, static segments win over
+/// `:param` segments, which win over `*`. Constrained parameters such as
+/// `:id<u64>` or `:slug<[a-z0-9-]+>` are tried before unconstrained ones,
+/// and a value that fails its constraint falls through to the next
+/// candidate. Otherwise routes are tried in registration order.
+#[derive(Clone, Default)]
 pub struct RouteMatcher {
-    routes: HashSet<RouteNode>,
+    // shared between the per-connection clones of `Server`
+    root: Arc<Node>,
+    // registration counter, used to list routes in registration order
+    registered: usize,
+    // routes bound to a `Host` header, exact hosts before wildcards
+    hosts: Arc<Vec<HostRoutes>>,
+    // route names, including those of the host routes, which share them
+    names: Arc<HashSet<String>>,
+}
+
+#[derive(Clone)]
+struct HostRoutes {
+    // `api.example.com`, or `*.example.com` for any subdomain
+    pattern: String,
+    routes: RouteMatcher,
+}
+
+impl HostRoutes {
+    fn is_wildcard(&self) -> bool {
+        self.pattern.starts_with("*.")
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        if self.is_wildcard() {
+            let suffix = &self.pattern.as_bytes()[1..];
+            host.len() > suffix.len()
+                && host.as_bytes()[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        } else {
+            host.eq_ignore_ascii_case(&self.pattern)
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    // constrained parameters first, each group in registration order
+    parameters: Vec<ParameterNode>,
+    wildcard: Option<Box<Node>>,
+    routes: Vec<RouteNode>,
+}
+
+#[derive(Clone)]
+struct ParameterNode {
+    // the constraint as written in the route, empty when unconstrained
+    source: String,
+    constraint: Option<Constraint>,
+    node: Node,
+}
+
+#[derive(Clone)]
+enum Constraint {
+    Type(fn(&str) -> bool),
+    Pattern(Regex),
+}
+
+impl Constraint {
+    fn parse(source: &str) -> Result<Constraint, regex::Error> {
+        macro_rules! types {
+            ($($t:ty),*) => {
+                match source {
+                    $(stringify!($t) => return Ok(Constraint::Type(|s| s.parse::<$t>().is_ok())),)*
+                    _ => {}
+                }
+            };
+        }
+        types!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool);
+
+        Regex::new(&format!("^(?:{})$", source)).map(Constraint::Pattern)
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Constraint::Type(parses) => parses(value),
+            Constraint::Pattern(regex) => regex.is_match(value),
+        }
+    }
 }
 
+impl ParameterNode {
+    fn accepts(&self, value: &str) -> bool {
+        self.constraint
+            .as_ref()
+            .is_none_or(|constraint| constraint.matches(value))
+    }
+}
+
+#[derive(Clone)]
 struct RouteNode {
+    index: usize,
     method: String,
-    handler: Arc<RouteHandler>,
     path: String,
-    segments: Vec<Segment>,
+    name: Option<String>,
+    // names of the `:param` and `*name` segments, in path order
+    parameters: Vec<String>,
+    handler: Arc<RouteHandler>,
+    middlewares: Vec<Arc<Middleware>>,
+    // attached with `Route::with`, run only when this route matches
+    around_middlewares: Vec<Arc<AroundMiddleware>>,
+}
+
+enum Segment<'a> {
+    Static(&'a str),
+    Parameter(&'a str, &'a str),
+    Wildcard(&'a str),
 }
 
-impl Clone for RouteNode {
-    fn clone(&self) -> Self {
-        RouteNode {
-            method: self.method.clone(),
-            path: self.path.clone(),
-            segments: self.segments.clone(),
-            handler: Arc::clone(&self.handler),
+impl<'a> Segment<'a> {
+    /// Fails on a constraint without its closing `>`, which is also what a
+    /// constraint containing `/` looks like once the path is split.
+    fn parse(s: &'a str) -> Result<Segment<'a>, String> {
+        if let Some(name) = s.strip_prefix(':') {
+            match name.split_once('<') {
+                Some((name, constraint)) => match constraint.strip_suffix('>') {
+                    Some(constraint) => Ok(Segment::Parameter(name, constraint)),
+                    None => Err(format!(
+                        "`{}` has no closing `>`, and constraints cannot contain `/`",
+                        s
+                    )),
+                },
+                None => Ok(Segment::Parameter(name, "")),
+            }
+        } else if let Some(name) = s.strip_prefix('*') {
+            Ok(Segment::Wildcard(name))
+        } else {
+            Ok(Segment::Static(s))
         }
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone)]
-enum Segment {
-    Static(String),
-    Parameter(String),
-    Wildcard,
+/// Returned when registering a route, to configure it further.
+pub struct Route<'a> {
+    route: &'a mut RouteNode,
+    names: &'a mut HashSet<String>,
 }
 
-impl Hash for RouteNode {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.method.hash(state);
-        self.path.hash(state);
+impl<'a> Route<'a> {
+    /// Names the route so that `Server::url_for` can build URLs for it.
+    ///
+    /// # Panics
+    ///
+    /// If another route already has this name.
+    pub fn name(mut self, name: &str) -> Self {
+        if let Err(e) = self.set_name(name) {
+            panic!("{}", e);
+        }
+        self
     }
-}
 
-impl PartialEq for RouteNode {
-    fn eq(&self, other: &Self) -> bool {
-        self.method == other.method && self.path == other.path
+    fn set_name(&mut self, name: &str) -> Result<(), RouteError> {
+        if self.route.name.as_deref() == Some(name) {
+            return Ok(());
+        }
+        if !self.names.insert(name.to_string()) {
+            return Err(RouteError::DuplicateName(name.to_string()));
+        }
+        if let Some(previous) = self.route.name.replace(name.to_string()) {
+            self.names.remove(&previous);
+        }
+        Ok(())
     }
-}
 
-impl Eq for RouteNode {}
+    /// Wraps the handler of this route only, after any global or group
+    /// middleware. Middleware attached first runs first.
+    pub fn with<F>(self, middleware: F) -> Self
+    where
+        F: Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route
+            .around_middlewares
+            .push(Arc::new(Box::new(middleware)));
+        self
+    }
+}
 
-pub struct MatchedRoute {
+/// A registered route, as listed by `Server::routes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub host: Option<String>,
     pub method: String,
     pub path: String,
+    pub name: Option<String>,
+}
+
+pub enum RouteMatch<'a> {
+    Found(MatchedRoute<'a>),
+    /// The path exists but has no route for the requested method; holds the
+    /// methods registered for it.
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+    /// The path or query string could not be decoded.
+    Invalid(RequestError),
+}
+
+pub struct MatchedRoute<'a> {
     pub parameters: HashMap<String, String>,
-    pub url_parameters: HashMap<String, String>,
-    pub handler: Arc<RouteHandler>,
+    pub url_parameters: Vec<(String, String)>,
+    pub handler: &'a RouteHandler,
+    pub middlewares: &'a [Arc<Middleware>],
+    pub around_middlewares: &'a [Arc<AroundMiddleware>],
 }
 
 impl RouteMatcher {
     pub fn new() -> RouteMatcher {
-        RouteMatcher {
-            routes: HashSet::new(),
+        RouteMatcher::default()
+    }
+
+    pub fn add_route(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: RouteHandler,
+        middlewares: Vec<Arc<Middleware>>,
+    ) -> Result<Route<'_>, RouteError> {
+        self.insert(method, path, Arc::new(handler), middlewares)
+    }
+
+    /// Every registered route, in registration order.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let mut routes = self
+            .sorted_routes()
+            .into_iter()
+            .map(|route| RouteInfo {
+                host: None,
+                method: route.method.clone(),
+                path: route.path.clone(),
+                name: route.name.clone(),
+            })
+            .collect::<Vec<_>>();
+        for host in self.hosts.iter() {
+            routes.extend(host.routes.routes().into_iter().map(|route| RouteInfo {
+                host: Some(host.pattern.clone()),
+                ..route
+            }));
+        }
+        routes
+    }
+
+    /// The routes bound to `host`, or the default routes when no host
+    /// pattern matches. Any port in `host` is ignored.
+    pub fn for_host(&self, host: Option<&str>) -> &RouteMatcher {
+        let host = match host {
+            Some(host) => strip_port(host),
+            None => return self,
+        };
+        self.hosts
+            .iter()
+            .find(|routes| routes.matches(host))
+            .map_or(self, |routes| &routes.routes)
+    }
+
+    /// Copies every route of `other` into the routes bound to `pattern`,
+    /// either an exact host or `*.domain` for any subdomain.
+    pub fn mount_host(&mut self, pattern: &str, other: &RouteMatcher) -> Result<(), RouteError> {
+        let wildcard = pattern.starts_with("*.");
+        if pattern.is_empty() || pattern[wildcard as usize..].contains('*') {
+            return Err(RouteError::InvalidHost(pattern.to_string()));
+        }
+
+        if let Some(name) = other.names.iter().find(|name| self.names.contains(*name)) {
+            return Err(RouteError::DuplicateName(name.clone()));
+        }
+
+        let hosts = Arc::make_mut(&mut self.hosts);
+        let index = match hosts
+            .iter()
+            .position(|host| host.pattern.eq_ignore_ascii_case(pattern))
+        {
+            Some(index) => index,
+            None => {
+                let index = if wildcard {
+                    hosts.len()
+                } else {
+                    hosts
+                        .iter()
+                        .position(HostRoutes::is_wildcard)
+                        .unwrap_or(hosts.len())
+                };
+                hosts.insert(
+                    index,
+                    HostRoutes {
+                        pattern: pattern.to_string(),
+                        routes: RouteMatcher::new(),
+                    },
+                );
+                index
+            }
+        };
+        hosts[index].routes.mount("/", other, &[])?;
+        Arc::make_mut(&mut self.names).extend(other.names.iter().cloned());
+        Ok(())
+    }
+
+    /// Builds the path of the route called `name`, filling its `:param` and
+    /// `*name` segments from `parameters`. Returns `None` if there is no such
+    /// route, a parameter is missing or the path would not reach the route,
+    /// e.g. because a value fails its constraint.
+    pub fn url_for(&self, name: &str, parameters: &[(&str, &str)]) -> Option<String> {
+        let route = self
+            .sorted_routes()
+            .into_iter()
+            .find(|route| route.name.as_deref() == Some(name));
+        let route = match route {
+            Some(route) => route,
+            None => {
+                return self
+                    .hosts
+                    .iter()
+                    .find_map(|host| host.routes.url_for(name, parameters))
+            }
+        };
+        let parameter = |name: &str| {
+            parameters
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| *value)
+        };
+
+        let mut url = String::new();
+        for segment in route.path.split('/').filter(|s| !s.is_empty()) {
+            url.push('/');
+            // registered paths always parse
+            match Segment::parse(segment).ok()? {
+                Segment::Static(s) => url.push_str(s),
+                Segment::Parameter(name, _) => url.push_str(&percent_encode(parameter(name)?)),
+                Segment::Wildcard(name) => {
+                    let value = parameter(name)?;
+                    let encoded = value.split('/').map(percent_encode).collect::<Vec<_>>();
+                    url.push_str(&encoded.join("/"));
+                }
+            }
+        }
+        if url.is_empty() {
+            url.push('/');
+        }
+        match self.match_route(&route.method, &url) {
+            RouteMatch::Found(matched) if std::ptr::eq(matched.handler, &*route.handler) => {
+                Some(url)
+            }
+            _ => None,
         }
     }
 
-    pub fn add_route(&mut self, method: &str, path: &str, handler: RouteHandler) {
+    fn sorted_routes(&self) -> Vec<&RouteNode> {
+        let mut routes = Vec::new();
+        self.root.collect_routes(&mut routes);
+        routes.sort_by_key(|route| route.index);
+        routes
+    }
+
+    /// Copies every route of `other` into this matcher under `prefix`, with
+    /// `middlewares` running before the routes' own middleware.
+    pub fn mount(
+        &mut self,
+        prefix: &str,
+        other: &RouteMatcher,
+        middlewares: &[Arc<Middleware>],
+    ) -> Result<(), RouteError> {
+        for route in other.sorted_routes() {
+            let mut route_middlewares = middlewares.to_vec();
+            route_middlewares.extend(route.middlewares.iter().cloned());
+            let mut mounted = self.insert(
+                &route.method,
+                &join_paths(prefix, &route.path),
+                Arc::clone(&route.handler),
+                route_middlewares,
+            )?;
+            if let Some(name) = &route.name {
+                mounted.set_name(name)?;
+            }
+            mounted.route.around_middlewares = route.around_middlewares.clone();
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: Arc<RouteHandler>,
+        middlewares: Vec<Arc<Middleware>>,
+    ) -> Result<Route<'_>, RouteError> {
+        // the tree ignores empty segments, so the path is kept in the only
+        // form `SlashPolicy::Strict` lets through: `/api/` becomes `/api`
         let segments = path
             .split('/')
             .filter(|s| !s.is_empty())
-            .map(|s| {
-                if s.starts_with(':') {
-                    Segment::Parameter(s[1..].to_string())
-                } else if s == "*" {
-                    Segment::Wildcard
-                } else {
-                    Segment::Static(s.to_string())
-                }
-            })
             .collect::<Vec<_>>();
-        self.routes.insert(RouteNode {
+        let path = format!("/{}", segments.join("/"));
+        let path = path.as_str();
+
+        let mut node = Arc::make_mut(&mut self.root);
+        let mut parameters = Vec::new();
+        let mut segments = segments.into_iter().peekable();
+
+        while let Some(segment) = segments.next() {
+            let segment = Segment::parse(segment)
+                .map_err(|e| RouteError::InvalidConstraint(path.to_string(), e))?;
+            match segment {
+                Segment::Static(s) => {
+                    node = node.statics.entry(s.to_string()).or_default();
+                }
+                Segment::Parameter(name, source) => {
+                    parameters.push(name.to_string());
+                    node = node.parameter_node(source).map_err(|e| {
+                        RouteError::InvalidConstraint(path.to_string(), e.to_string())
+                    })?;
+                }
+                Segment::Wildcard(name) => {
+                    if segments.peek().is_some() {
+                        return Err(RouteError::WildcardNotLast(path.to_string()));
+                    }
+                    // an unnamed `*` matches without capturing
+                    if !name.is_empty() {
+                        parameters.push(name.to_string());
+                    }
+                    node = node.wildcard.get_or_insert_with(Default::default);
+                }
+            }
+        }
+
+        // parameter names are not part of the tree, so `/a/:x` and `/a/:y`
+        // end on the same node and could never be told apart; a `*` route
+        // answers every method, so it overlaps any other route on the node
+        let overlaps =
+            |route: &&RouteNode| route.method == method || route.method == "*" || method == "*";
+        if let Some(existing) = node.routes.iter().find(overlaps) {
+            return Err(if existing.method == method && existing.path == path {
+                RouteError::Duplicate(method.to_string(), path.to_string())
+            } else {
+                RouteError::Conflict(
+                    method.to_string(),
+                    path.to_string(),
+                    format!("{} {}", existing.method, existing.path),
+                )
+            });
+        }
+
+        self.registered += 1;
+        node.routes.push(RouteNode {
+            index: self.registered,
             method: method.to_string(),
             path: path.to_string(),
-            segments,
-            handler: Arc::new(handler),
+            name: None,
+            parameters,
+            handler,
+            middlewares,
+            around_middlewares: Vec::new(),
         });
+        Ok(Route {
+            route: node.routes.last_mut().unwrap(),
+            names: Arc::make_mut(&mut self.names),
+        })
     }
 
-    pub fn match_route(&self, method: &str, url: &str) -> Option<MatchedRoute> {
-        let (path, query_string) = url.split_at(url.find('?').unwrap_or_else(|| url.len()));
-        let segments = path
+    pub fn match_route(&self, method: &str, url: &str) -> RouteMatch<'_> {
+        let (path, query_string) = url.split_at(url.find('?').unwrap_or(url.len()));
+        // segments are decoded before matching so that `%2F` stays in its segment
+        let decoded = match path
             .split('/')
             .filter(|s| !s.is_empty())
-            .collect::<Vec<_>>();
+            .map(|s| percent_decode(s, false))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(decoded) => decoded,
+            Err(e) => return RouteMatch::Invalid(e),
+        };
+        let segments = decoded.iter().map(|s| s.as_ref()).collect::<Vec<_>>();
 
-        for route in &self.routes {
-            if let Some(parameters) = route.match_segments(&segments) {
-                let url_parameters = query_string
-                    .trim_start_matches('?')
-                    .split('&')
-                    .filter(|s| !s.is_empty())
-                    .map(|s| {
-                        let mut parts = s.split('=');
-                        (
-                            parts.next().unwrap().to_string(),
-                            parts.next().unwrap_or("").to_string(),
-                        )
-                    })
-                    .collect::<HashMap<_, _>>();
-
-                if &route.method != method && route.method != "*" {
-                    continue;
+        let mut values = Vec::new();
+        let route = match self.root.lookup(&segments, method, &mut values) {
+            Some(route) => route,
+            None => {
+                let mut allowed = Vec::new();
+                self.root.allowed_methods(&segments, &mut allowed);
+                if allowed.is_empty() {
+                    return RouteMatch::NotFound;
+                }
+                // HEAD falls back to GET and OPTIONS is answered automatically
+                if allowed.iter().any(|m| m == "GET") && !allowed.iter().any(|m| m == "HEAD") {
+                    allowed.push("HEAD".to_string());
                 }
-                return Some(MatchedRoute {
-                    method: route.method.clone(),
-                    path: route.path.clone(),
-                    parameters,
-                    url_parameters,
-                    handler: Arc::clone(&route.handler),
-                });
+                if !allowed.iter().any(|m| m == "OPTIONS") {
+                    allowed.push("OPTIONS".to_string());
+                }
+                return RouteMatch::MethodNotAllowed(allowed);
+            }
+        };
+
+        let url_parameters = match parse_query(query_string) {
+            Ok(url_parameters) => url_parameters,
+            Err(e) => return RouteMatch::Invalid(e),
+        };
+        let parameters = route
+            .parameters
+            .iter()
+            .cloned()
+            .zip(values.into_iter().map(Cow::into_owned))
+            .collect::<HashMap<_, _>>();
+
+        RouteMatch::Found(MatchedRoute {
+            parameters,
+            url_parameters,
+            handler: &route.handler,
+            middlewares: &route.middlewares,
+            around_middlewares: &route.around_middlewares,
+        })
+    }
+}
+
+impl Node {
+    /// Depth-first search that backtracks into the parameter and wildcard
+    /// children when a more specific branch does not lead to a route.
+    fn lookup<'n, 'u>(
+        &'n self,
+        segments: &[&'u str],
+        method: &str,
+        values: &mut Vec<Cow<'u, str>>,
+    ) -> Option<&'n RouteNode> {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return self.route_for(method),
+        };
+
+        if let Some(child) = self.statics.get(*segment) {
+            if let Some(route) = child.lookup(rest, method, values) {
+                return Some(route);
+            }
+        }
+
+        for child in &self.parameters {
+            if !child.accepts(segment) {
+                continue;
+            }
+            values.push(Cow::Borrowed(segment));
+            if let Some(route) = child.node.lookup(rest, method, values) {
+                return Some(route);
+            }
+            values.pop();
+        }
+
+        if let Some(child) = &self.wildcard {
+            if let Some(route) = child.route_for(method) {
+                // the wildcard captures the rest of the path, slashes included
+                values.push(Cow::Owned(segments.join("/")));
+                return Some(route);
             }
         }
 
         None
     }
+
+    /// Collects the methods of every route whose path matches `segments`.
+    fn allowed_methods(&self, segments: &[&str], allowed: &mut Vec<String>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return self.push_methods(allowed),
+        };
+
+        if let Some(child) = self.statics.get(*segment) {
+            child.allowed_methods(rest, allowed);
+        }
+        for child in &self.parameters {
+            if child.accepts(segment) {
+                child.node.allowed_methods(rest, allowed);
+            }
+        }
+        if let Some(child) = &self.wildcard {
+            child.push_methods(allowed);
+        }
+    }
+
+    fn parameter_node(&mut self, source: &str) -> Result<&mut Node, regex::Error> {
+        let index = match self.parameters.iter().position(|p| p.source == source) {
+            Some(index) => index,
+            None => {
+                let constraint = if source.is_empty() {
+                    None
+                } else {
+                    Some(Constraint::parse(source)?)
+                };
+                // unconstrained parameters stay behind the constrained ones
+                let index = if constraint.is_some() {
+                    self.parameters
+                        .iter()
+                        .position(|p| p.constraint.is_none())
+                        .unwrap_or(self.parameters.len())
+                } else {
+                    self.parameters.len()
+                };
+                self.parameters.insert(
+                    index,
+                    ParameterNode {
+                        source: source.to_string(),
+                        constraint,
+                        node: Node::default(),
+                    },
+                );
+                index
+            }
+        };
+        Ok(&mut self.parameters[index].node)
+    }
+
+    fn collect_routes<'n>(&'n self, routes: &mut Vec<&'n RouteNode>) {
+        routes.extend(self.routes.iter());
+        for child in self.statics.values() {
+            child.collect_routes(routes);
+        }
+        for child in &self.parameters {
+            child.node.collect_routes(routes);
+        }
+        if let Some(child) = &self.wildcard {
+            child.collect_routes(routes);
+        }
+    }
+
+    fn push_methods(&self, allowed: &mut Vec<String>) {
+        for route in &self.routes {
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method.clone());
+            }
+        }
+    }
+
+    fn route_for(&self, method: &str) -> Option<&RouteNode> {
+        let route = self
+            .routes
+            .iter()
+            .find(|route| route.method == method || route.method == "*");
+        if route.is_none() && method == "HEAD" {
+            return self.route_for("GET");
+        }
+        route
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        // `[::1]` has colons but no port
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
 }
 
-impl RouteNode {
-    fn match_segments(&self, segments: &[&str]) -> Option<HashMap<String, String>> {
-        if self.segments.len() != segments.len() && !self.segments.contains(&Segment::Wildcard) {
-            return None;
+/// The URL with empty path segments removed, or `None` if it has none.
+pub(crate) fn canonical_path(url: &str) -> Option<String> {
+    let (path, query_string) = url.split_at(url.find('?').unwrap_or(url.len()));
+    if path == "/" || !(path.contains("//") || path.ends_with('/')) {
+        return None;
+    }
+
+    let mut canonical = String::with_capacity(url.len());
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        canonical.push('/');
+        canonical.push_str(segment);
+    }
+    if canonical.is_empty() {
+        canonical.push('/');
+    }
+    canonical.push_str(query_string);
+    Some(canonical)
+}
+
+pub(crate) fn join_paths(prefix: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        prefix.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(routes: &[(&str, &str)]) -> RouteMatcher {
+        let mut matcher = RouteMatcher::new();
+        for (method, path) in routes {
+            matcher
+                .add_route(method, path, Box::new(|_, _| Ok(())), Vec::new())
+                .unwrap();
         }
+        matcher
+    }
 
-        let mut parameters = HashMap::new();
-        let mut wildcard = false;
+    fn parameters(
+        matcher: &RouteMatcher,
+        method: &str,
+        url: &str,
+    ) -> Option<Vec<(String, String)>> {
+        let matched = match matcher.match_route(method, url) {
+            RouteMatch::Found(matched) => matched,
+            _ => return None,
+        };
+        let mut parameters = matched.parameters.into_iter().collect::<Vec<_>>();
+        parameters.sort();
+        Some(parameters)
+    }
 
-        for (route_segment, segment) in self.segments.iter().zip(segments.iter()) {
-            match route_segment {
-                Segment::Static(s) => {
-                    if s != segment {
-                        return None;
-                    }
-                }
-                Segment::Parameter(param) => {
-                    parameters.insert(param.clone(), (*segment).to_string());
-                }
-                Segment::Wildcard => {
-                    wildcard = true;
-                    break;
-                }
+    #[test]
+    fn static_beats_parameter_regardless_of_registration_order() {
+        for routes in [
+            [("GET", "/user/me"), ("GET", "/user/:id")],
+            [("GET", "/user/:id"), ("GET", "/user/me")],
+        ] {
+            let matcher = matcher(&routes);
+            assert_eq!(parameters(&matcher, "GET", "/user/me"), Some(vec![]));
+            assert_eq!(
+                parameters(&matcher, "GET", "/user/42"),
+                Some(vec![("id".to_string(), "42".to_string())])
+            );
+        }
+    }
+
+    #[test]
+    fn parameter_beats_wildcard() {
+        let matcher = matcher(&[("GET", "/files/*"), ("GET", "/files/:name")]);
+        assert_eq!(
+            parameters(&matcher, "GET", "/files/a"),
+            Some(vec![("name".to_string(), "a".to_string())])
+        );
+        assert_eq!(parameters(&matcher, "GET", "/files/a/b"), Some(vec![]));
+    }
+
+    #[test]
+    fn backtracks_when_static_branch_has_no_route() {
+        let matcher = matcher(&[("GET", "/user/me"), ("GET", "/user/:id/posts")]);
+        assert_eq!(
+            parameters(&matcher, "GET", "/user/me/posts"),
+            Some(vec![("id".to_string(), "me".to_string())])
+        );
+    }
+
+    #[test]
+    fn backtracks_when_static_branch_has_other_method() {
+        let matcher = matcher(&[("GET", "/user/me"), ("POST", "/user/:id")]);
+        assert_eq!(
+            parameters(&matcher, "POST", "/user/me"),
+            Some(vec![("id".to_string(), "me".to_string())])
+        );
+    }
+
+    #[test]
+    fn reports_allowed_methods_for_known_path() {
+        let matcher = matcher(&[
+            ("GET", "/user/me"),
+            ("DELETE", "/user/:id"),
+            ("GET", "/user/:id"),
+        ]);
+        match matcher.match_route("POST", "/user/me") {
+            RouteMatch::MethodNotAllowed(allowed) => {
+                assert_eq!(allowed, ["GET", "DELETE", "HEAD", "OPTIONS"])
             }
+            _ => panic!("expected 405"),
         }
+        assert!(matches!(
+            matcher.match_route("POST", "/unknown"),
+            RouteMatch::NotFound
+        ));
+    }
 
-        if wildcard {
-            Some(parameters)
-        } else if self.segments.len() == segments.len() {
-            Some(parameters)
-        } else {
-            None
+    #[test]
+    fn head_falls_back_to_get() {
+        let matcher = matcher(&[("GET", "/user/:id")]);
+        assert_eq!(
+            parameters(&matcher, "HEAD", "/user/42"),
+            Some(vec![("id".to_string(), "42".to_string())])
+        );
+        assert!(matches!(
+            matcher.match_route("OPTIONS", "/user/42"),
+            RouteMatch::MethodNotAllowed(_)
+        ));
+    }
+
+    #[test]
+    fn mounted_routes_resolve_relative_to_prefix() {
+        let billing = matcher(&[("GET", "/"), ("GET", "/invoices/:id"), ("GET", "/files/*")]);
+        let mut app = matcher(&[("GET", "/invoices/:id")]);
+        app.mount("/billing", &billing, &[]).unwrap();
+
+        assert_eq!(parameters(&app, "GET", "/billing"), Some(vec![]));
+        assert_eq!(
+            parameters(&app, "GET", "/billing/invoices/7"),
+            Some(vec![("id".to_string(), "7".to_string())])
+        );
+        assert_eq!(parameters(&app, "GET", "/billing/files/a/b"), Some(vec![]));
+        assert!(matches!(
+            app.match_route("GET", "/files/a"),
+            RouteMatch::NotFound
+        ));
+    }
+
+    #[test]
+    fn named_wildcard_captures_remaining_path() {
+        let matcher = matcher(&[("GET", "/static/*file"), ("GET", "/assets/*")]);
+        assert_eq!(
+            parameters(&matcher, "GET", "/static/css/site.css"),
+            Some(vec![("file".to_string(), "css/site.css".to_string())])
+        );
+        assert_eq!(parameters(&matcher, "GET", "/assets/a/b"), Some(vec![]));
+    }
+
+    #[test]
+    fn wildcard_must_be_last_segment() {
+        let mut matcher = RouteMatcher::new();
+        let result = matcher.add_route("GET", "/a/*rest/b", Box::new(|_, _| Ok(())), Vec::new());
+        assert!(matches!(result, Err(RouteError::WildcardNotLast(_))));
+    }
+
+    #[test]
+    fn constrained_parameters_fall_through_on_mismatch() {
+        let matcher = matcher(&[
+            ("GET", "/post/:slug"),
+            ("GET", "/post/:id<u64>"),
+            ("GET", "/tag/:tag<[a-z0-9-]+>"),
+        ]);
+        assert_eq!(
+            parameters(&matcher, "GET", "/post/42"),
+            Some(vec![("id".to_string(), "42".to_string())])
+        );
+        assert_eq!(
+            parameters(&matcher, "GET", "/post/hello"),
+            Some(vec![("slug".to_string(), "hello".to_string())])
+        );
+        assert_eq!(
+            parameters(&matcher, "GET", "/tag/rust-lang"),
+            Some(vec![("tag".to_string(), "rust-lang".to_string())])
+        );
+        assert!(matches!(
+            matcher.match_route("GET", "/tag/Rust"),
+            RouteMatch::NotFound
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_constraints() {
+        let mut matcher = RouteMatcher::new();
+        let mut add = |path| {
+            matcher
+                .add_route("GET", path, Box::new(|_, _| Ok(())), Vec::new())
+                .map(|_| ())
+        };
+        assert!(matches!(
+            add("/a/:id<u64"),
+            Err(RouteError::InvalidConstraint(..))
+        ));
+        assert!(matches!(
+            add("/a/:p<x/y>"),
+            Err(RouteError::InvalidConstraint(..))
+        ));
+        assert!(matches!(
+            add("/a/:p<[^/]+>"),
+            Err(RouteError::InvalidConstraint(..))
+        ));
+        assert!(matches!(
+            add("/a/:p<(>"),
+            Err(RouteError::InvalidConstraint(..))
+        ));
+        assert!(add("/a/:id<u64>").is_ok());
+    }
+
+    #[test]
+    fn decodes_path_segments_and_query() {
+        let matcher = matcher(&[("GET", "/files/:name"), ("GET", "/café")]);
+        assert_eq!(
+            parameters(&matcher, "GET", "/files/a%2Fb"),
+            Some(vec![("name".to_string(), "a/b".to_string())])
+        );
+        assert_eq!(parameters(&matcher, "GET", "/caf%C3%A9"), Some(vec![]));
+
+        match matcher.match_route("GET", "/files/x?q=hello%20world+again&e=a%3Db") {
+            RouteMatch::Found(matched) => {
+                assert_eq!(
+                    matched.url_parameters,
+                    [
+                        ("q".to_string(), "hello world again".to_string()),
+                        ("e".to_string(), "a=b".to_string())
+                    ]
+                );
+            }
+            _ => panic!("expected a match"),
         }
+        assert!(matches!(
+            matcher.match_route("GET", "/files/%FF"),
+            RouteMatch::Invalid(RequestError::Utf8Error(_))
+        ));
+    }
+
+    #[test]
+    fn lists_routes_and_builds_urls() {
+        let mut matcher = matcher(&[("GET", "/")]);
+        matcher
+            .add_route("GET", "/user/:id<u64>", Box::new(|_, _| Ok(())), Vec::new())
+            .unwrap()
+            .name("user_detail");
+        matcher
+            .add_route("GET", "/files/*path", Box::new(|_, _| Ok(())), Vec::new())
+            .unwrap()
+            .name("file");
+
+        let routes = matcher.routes();
+        assert_eq!(
+            routes.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+            ["/", "/user/:id<u64>", "/files/*path"]
+        );
+        assert_eq!(routes[1].name.as_deref(), Some("user_detail"));
+
+        assert_eq!(
+            matcher.url_for("user_detail", &[("id", "42")]).as_deref(),
+            Some("/user/42")
+        );
+        assert_eq!(
+            matcher.url_for("file", &[("path", "a b/c.txt")]).as_deref(),
+            Some("/files/a%20b/c.txt")
+        );
+        assert_eq!(matcher.url_for("user_detail", &[]), None);
+        assert_eq!(matcher.url_for("user_detail", &[("id", "abc")]), None);
+        assert_eq!(matcher.url_for("missing", &[]), None);
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let mut module = RouteMatcher::new();
+        module
+            .add_route("GET", "/list", Box::new(|_, _| Ok(())), Vec::new())
+            .unwrap()
+            .name("list");
+        let mut other = module
+            .add_route("GET", "/other", Box::new(|_, _| Ok(())), Vec::new())
+            .unwrap();
+        assert!(matches!(
+            other.set_name("list"),
+            Err(RouteError::DuplicateName(_))
+        ));
+        assert!(other.set_name("other").is_ok());
+        assert!(other.set_name("renamed").is_ok());
+
+        let mut app = RouteMatcher::new();
+        app.mount("/a", &module, &[]).unwrap();
+        assert!(matches!(
+            app.mount("/b", &module, &[]),
+            Err(RouteError::DuplicateName(_))
+        ));
+        assert!(matches!(
+            app.mount_host("api.example.com", &module),
+            Err(RouteError::DuplicateName(_))
+        ));
+
+        let mut hosts = RouteMatcher::new();
+        hosts.mount_host("api.example.com", &module).unwrap();
+        let mut root = hosts
+            .add_route("GET", "/", Box::new(|_, _| Ok(())), Vec::new())
+            .unwrap();
+        assert!(matches!(
+            root.set_name("renamed"),
+            Err(RouteError::DuplicateName(_))
+        ));
+        assert!(root.set_name("other").is_ok());
+        assert_eq!(hosts.url_for("list", &[]).as_deref(), Some("/list"));
+    }
+
+    #[test]
+    fn rejects_duplicate_and_ambiguous_routes() {
+        let mut matcher = matcher(&[("GET", "/a/:x"), ("GET", "/b")]);
+        let mut add = |method, path| {
+            matcher
+                .add_route(method, path, Box::new(|_, _| Ok(())), Vec::new())
+                .map(|_| ())
+        };
+        assert!(matches!(add("GET", "/b"), Err(RouteError::Duplicate(..))));
+        assert!(matches!(add("GET", "/a/:y"), Err(RouteError::Conflict(..))));
+        assert!(add("POST", "/a/:y").is_ok());
+        assert!(add("GET", "/a/:y<u64>").is_ok());
+
+        // `*` overlaps every method, whichever is registered first
+        assert!(add("*", "/c").is_ok());
+        assert!(matches!(add("GET", "/c"), Err(RouteError::Conflict(..))));
+        assert!(matches!(add("*", "/b"), Err(RouteError::Conflict(..))));
+        assert!(matches!(add("*", "/c"), Err(RouteError::Duplicate(..))));
+    }
+
+    #[test]
+    fn dispatches_by_host() {
+        let api = matcher(&[("GET", "/users/:id")]);
+        let tenants = matcher(&[("GET", "/")]);
+        let mut app = matcher(&[("GET", "/")]);
+        app.mount_host("*.example.com", &tenants).unwrap();
+        app.mount_host("api.example.com", &api).unwrap();
+
+        let api = app.for_host(Some("API.example.com:8080"));
+        assert_eq!(
+            parameters(api, "GET", "/users/1"),
+            Some(vec![("id".to_string(), "1".to_string())])
+        );
+        assert!(matches!(api.match_route("GET", "/"), RouteMatch::NotFound));
+
+        let tenant = app.for_host(Some("acme.example.com"));
+        assert_eq!(parameters(tenant, "GET", "/"), Some(vec![]));
+        assert!(matches!(
+            tenant.match_route("GET", "/users/1"),
+            RouteMatch::NotFound
+        ));
+
+        let default = app.for_host(Some("example.com"));
+        assert_eq!(parameters(default, "GET", "/"), Some(vec![]));
+        assert!(matches!(
+            default.match_route("GET", "/users/1"),
+            RouteMatch::NotFound
+        ));
+        assert!(app.mount_host("api.*.com", &tenants).is_err());
+    }
+
+    #[test]
+    fn canonicalizes_slashes() {
+        assert_eq!(canonical_path("/users/").as_deref(), Some("/users"));
+        assert_eq!(canonical_path("//users").as_deref(), Some("/users"));
+        assert_eq!(canonical_path("/a//b/").as_deref(), Some("/a/b"));
+        assert_eq!(canonical_path("/users/?a=b").as_deref(), Some("/users?a=b"));
+        assert_eq!(canonical_path("//").as_deref(), Some("/"));
+        assert_eq!(canonical_path("/"), None);
+        assert_eq!(canonical_path("/?a=b/"), None);
+        assert_eq!(canonical_path("/users"), None);
+        assert_eq!(canonical_path("/users?next=/a//b/"), None);
     }
 }
@@ -1,124 +1,800 @@
-
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
 
-use crate::{http::http_server::{HttpServer, HttpService }, request::request::{RawRequest,Request}, response::response::Response, router::route_matcher::RouteMatcher};
+use crate::server::access_log::AccessLog;
+use crate::{
+    errors::errors::RouteError,
+    http::http_server::{HttpServer, HttpService},
+    request::{
+        request::{RawRequest, Request},
+        url::parse_query,
+    },
+    response::response::Response,
+    router::route_matcher::{
+        canonical_path, join_paths, Route, RouteInfo, RouteMatch, RouteMatcher,
+    },
+};
 
+macro_rules! route_methods {
+    () => {
+        route_methods!(
+            get => "GET",
+            post => "POST",
+            put => "PUT",
+            delete => "DELETE",
+            head => "HEAD",
+            options => "OPTIONS",
+            trace => "TRACE",
+            connect => "CONNECT",
+            patch => "PATCH"
+        );
+    };
+    ($($name:ident => $method:literal),*) => {
+        $(
+            pub fn $name<F>(&mut self, path: &str, handler: F) -> Route<'_>
+            where
+                F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+            {
+                self.add_route_handler($method, path, handler)
+            }
+        )*
+    };
+}
+
+/// Runs before a route handler, or before routing when registered with
+/// `Server::use_middleware`. A middleware ends the request early by writing a
+/// response (setting a status code or a body); adding headers alone lets the
+/// request continue.
 pub type Middleware =
     Box<dyn Fn(&RawRequest, &mut Response) -> io::Result<()> + Send + Sync + 'static>;
 
+/// Runs after the response has been produced and before it is encoded, so
+/// it can still change the status, headers or body.
+pub type AfterHook = Box<dyn Fn(&mut Response) -> io::Result<()> + Send + Sync + 'static>;
+
+/// Wraps the rest of the request: it calls `next.run(req, res)` to continue
+/// with the remaining middleware and the route handler, and may act on the
+/// result, e.g. to time the request, turn an error into a response or run
+/// the rest again to retry it.
+pub type AroundMiddleware =
+    Box<dyn Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static>;
+
 pub type RouteHandler =
     Box<dyn Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static>;
 
-#[derive(Clone)]
+/// The remainder of the chain, handed to an `AroundMiddleware`.
+///
+/// It can be run more than once to retry the request. Each run starts from
+/// the response as the previous one left it, and a body already read by the
+/// handler is not read again.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middlewares: &'a [Arc<AroundMiddleware>],
+    endpoint: &'a (dyn Fn(&mut Request, &mut Response) -> io::Result<()> + 'a),
+}
+
+impl<'a> Next<'a> {
+    /// Runs the next middleware, or the endpoint once none are left.
+    pub fn run(&self, req: &mut Request, res: &mut Response) -> io::Result<()> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware(
+                req,
+                res,
+                Next {
+                    middlewares,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(req, res),
+        }
+    }
+}
+
+/// How paths with a trailing slash or repeated slashes, such as `/users/`
+/// or `//users`, are matched against the canonical `/users`. Routes are
+/// registered in canonical form, so a route written as `/users/` is also
+/// served at `/users`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlashPolicy {
+    /// Only the canonical path matches; other forms are not found.
+    Strict,
+    /// Empty segments are ignored, so every form matches.
+    #[default]
+    Merge,
+    /// Other forms are redirected to the canonical path with a 301.
+    MovedPermanently,
+    /// Other forms are redirected to the canonical path with a 308, which
+    /// keeps the method and body.
+    PermanentRedirect,
+}
+
+#[derive(Clone, Default)]
 pub struct Server {
     route_handlers: RouteMatcher,
+    fallback: Option<Arc<RouteHandler>>,
+    slash_policy: SlashPolicy,
+    stack_size: Option<usize>,
+    trust_request_id: bool,
+    access_log: Option<Arc<AccessLog>>,
+    middlewares: Vec<Arc<Middleware>>,
+    around_middlewares: Vec<Arc<AroundMiddleware>>,
+    after_hooks: Vec<Arc<AfterHook>>,
 }
 
 impl Server {
     pub fn new() -> Self {
         Server {
             route_handlers: RouteMatcher::new(),
+            fallback: None,
+            slash_policy: SlashPolicy::Merge,
+            stack_size: None,
+            trust_request_id: false,
+            access_log: None,
+            middlewares: Vec::new(),
+            around_middlewares: Vec::new(),
+            after_hooks: Vec::new(),
         }
     }
 
+    /// Adds a middleware that runs for every request, in registration order,
+    /// before the request is routed.
+    pub fn use_middleware<F>(&mut self, middleware: F)
+    where
+        F: Fn(&RawRequest, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.middlewares.push(Arc::new(Box::new(middleware)));
+    }
+
+    /// Adds a middleware that wraps routing and the route handler of every
+    /// request, in registration order after the `use_middleware` ones. Path
+    /// parameters are not known yet when it runs.
+    pub fn around<F>(&mut self, middleware: F)
+    where
+        F: Fn(&mut Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.around_middlewares.push(Arc::new(Box::new(middleware)));
+    }
+
+    /// Adds a hook that runs for every response, in registration order,
+    /// including responses written by a middleware. Hooks do not run when a
+    /// middleware, handler or earlier hook returns an error; the server then
+    /// answers with a 500 instead.
+    pub fn after<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.after_hooks.push(Arc::new(Box::new(hook)));
+    }
+
+    pub fn slash_policy(&mut self, policy: SlashPolicy) {
+        self.slash_policy = policy;
+    }
+
+    /// Reuses the `X-Request-Id` header of incoming requests instead of
+    /// generating a new ID, for servers behind a proxy that sets it.
+    /// Malformed IDs are still replaced.
+    pub fn trust_request_id(&mut self, trust: bool) {
+        self.trust_request_id = trust;
+    }
+
+    /// Records every request once its response has been encoded.
+    pub fn access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(Arc::new(access_log));
+    }
+
+    /// The stack size, in bytes, of the coroutine serving each connection.
+    /// Handlers that keep large buffers on the stack may need more than
+    /// may's default.
+    pub fn stack_size(&mut self, bytes: usize) {
+        self.stack_size = Some(bytes);
+    }
+
     pub fn listen(&mut self, addr: &str) -> io::Result<()> {
-        may::config().set_workers(8);
+        let config = may::config();
+        config.set_workers(8);
+        if let Some(stack_size) = self.stack_size {
+            config.set_stack_size(stack_size / std::mem::size_of::<usize>());
+        }
         let server = HttpServer(self.clone()).start(addr)?;
         server.wait();
         Ok(())
     }
 
-    pub fn add_route_handler<F>(&mut self, method: &str, path: &str, handler: F)
+    pub fn add_route_handler<F>(&mut self, method: &str, path: &str, handler: F) -> Route<'_>
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.route_handlers
-            .add_route(method, path, Box::new(handler));
+        registered(
+            self.route_handlers
+                .add_route(method, path, Box::new(handler), Vec::new()),
+        )
     }
 
-    pub fn get<F>(&mut self, path: &str, handler: F)
+    /// Handles requests that match no route, instead of the default empty
+    /// 404 response.
+    pub fn fallback<F>(&mut self, handler: F)
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("GET", path, handler);
+        self.fallback = Some(Arc::new(Box::new(handler)));
     }
 
-    pub fn post<F>(&mut self, path: &str, handler: F)
-    where
-        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
-    {
-        self.add_route_handler("POST", path, handler);
+    /// Serves `router` for requests whose `Host` header matches `pattern`,
+    /// either an exact host such as `api.example.com` or `*.example.com` for
+    /// any subdomain. Other hosts are served by the routes of the server.
+    pub fn host(&mut self, pattern: &str, router: Router) {
+        registered(
+            self.route_handlers
+                .mount_host(pattern, &router.route_handlers),
+        );
     }
 
-    pub fn put<F>(&mut self, path: &str, handler: F)
+    pub fn group<F>(&mut self, prefix: &str, f: F)
     where
-        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+        F: FnOnce(&mut Group),
     {
-        self.add_route_handler("PUT", path, handler);
+        let mut group = Group {
+            route_handlers: &mut self.route_handlers,
+            prefix: prefix.to_string(),
+            middlewares: Vec::new(),
+        };
+        f(&mut group);
+    }
+
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        registered(
+            self.route_handlers
+                .mount(prefix, &router.route_handlers, &[]),
+        );
+    }
+
+    /// Every registered route, in registration order.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.route_handlers.routes()
+    }
+
+    /// Builds the path of a named route, e.g.
+    /// `app.url_for("user_detail", &[("id", "42")])` gives `/user/42`.
+    pub fn url_for(&self, name: &str, parameters: &[(&str, &str)]) -> Option<String> {
+        self.route_handlers.url_for(name, parameters)
     }
 
-    pub fn delete<F>(&mut self, path: &str, handler: F)
+    route_methods!();
+}
+
+/// A set of routes built independently of a `Server`, so that a module can
+/// expose its routes and the application can `mount` them under a prefix.
+/// Paths, parameters and wildcards are resolved relative to the mount point.
+#[derive(Clone, Default)]
+pub struct Router {
+    route_handlers: RouteMatcher,
+    middlewares: Vec<Arc<Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Adds a middleware that runs before the handler of every route
+    /// registered on this router afterwards.
+    pub fn middleware<F>(&mut self, middleware: F)
     where
-        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+        F: Fn(&RawRequest, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("DELETE", path, handler);
+        self.middlewares.push(Arc::new(Box::new(middleware)));
     }
 
-    pub fn head<F>(&mut self, path: &str, handler: F)
+    pub fn group<F>(&mut self, prefix: &str, f: F)
     where
-        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+        F: FnOnce(&mut Group),
     {
-        self.add_route_handler("HEAD", path, handler);
+        let mut group = Group {
+            route_handlers: &mut self.route_handlers,
+            prefix: prefix.to_string(),
+            middlewares: self.middlewares.clone(),
+        };
+        f(&mut group);
+    }
+
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        registered(
+            self.route_handlers
+                .mount(prefix, &router.route_handlers, &self.middlewares),
+        );
     }
 
-    pub fn options<F>(&mut self, path: &str, handler: F)
+    pub fn add_route_handler<F>(&mut self, method: &str, path: &str, handler: F) -> Route<'_>
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("OPTIONS", path, handler);
+        registered(self.route_handlers.add_route(
+            method,
+            path,
+            Box::new(handler),
+            self.middlewares.clone(),
+        ))
     }
 
-    pub fn trace<F>(&mut self, path: &str, handler: F)
+    route_methods!();
+}
+
+/// A set of routes sharing a path prefix and a list of middleware, created
+/// with `Server::group`.
+pub struct Group<'a> {
+    route_handlers: &'a mut RouteMatcher,
+    prefix: String,
+    middlewares: Vec<Arc<Middleware>>,
+}
+
+impl<'a> Group<'a> {
+    /// Adds a middleware that runs before the handler of every route
+    /// registered on this group afterwards, including nested groups.
+    pub fn middleware<F>(&mut self, middleware: F)
     where
-        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+        F: Fn(&RawRequest, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("TRACE", path, handler);
+        self.middlewares.push(Arc::new(Box::new(middleware)));
     }
 
-    pub fn connect<F>(&mut self, path: &str, handler: F)
+    pub fn group<F>(&mut self, prefix: &str, f: F)
     where
-        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+        F: FnOnce(&mut Group),
     {
-        self.add_route_handler("CONNECT", path, handler);
+        let mut group = Group {
+            route_handlers: &mut *self.route_handlers,
+            prefix: join_paths(&self.prefix, prefix),
+            middlewares: self.middlewares.clone(),
+        };
+        f(&mut group);
     }
 
-    pub fn patch<F>(&mut self, path: &str, handler: F)
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        registered(self.route_handlers.mount(
+            &join_paths(&self.prefix, prefix),
+            &router.route_handlers,
+            &self.middlewares,
+        ));
+    }
+
+    pub fn add_route_handler<F>(&mut self, method: &str, path: &str, handler: F) -> Route<'_>
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("PATCH", path, handler);
+        registered(self.route_handlers.add_route(
+            method,
+            &join_paths(&self.prefix, path),
+            Box::new(handler),
+            self.middlewares.clone(),
+        ))
+    }
+
+    route_methods!();
+}
+
+// Route registration errors are programming mistakes, so they abort startup.
+fn registered<T>(result: Result<T, RouteError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => panic!("{}", e),
     }
 }
 
 impl HttpService for Server {
-    fn handler(&mut self, req: RawRequest, res: &mut Response) -> io::Result<()> {
+    fn handler(&mut self, mut req: RawRequest, res: &mut Response) -> io::Result<()> {
+        req.request_id = match req.header("x-request-id") {
+            Some(id) if self.trust_request_id && valid_request_id(id) => id.to_string(),
+            _ => generate_request_id(),
+        };
+        res.set_request_id(&req.request_id);
+
+        if req.method() == "HEAD" {
+            res.skip_body();
+        }
+
+        for middleware in &self.middlewares {
+            middleware(&req, res)?;
+            if res.is_written() {
+                break;
+            }
+        }
+        if !res.is_written() {
+            let next = Next {
+                middlewares: &self.around_middlewares,
+                endpoint: &|req, res| self.dispatch(req, res),
+            };
+            next.run(&mut Request::new(req), res)?;
+        }
+        for hook in &self.after_hooks {
+            hook(res)?;
+        }
+        Ok(())
+    }
+
+    fn access_logger(&self) -> Option<&AccessLog> {
+        self.access_log.as_deref()
+    }
+}
+
+impl Server {
+    fn dispatch(&self, req: &mut Request, res: &mut Response) -> io::Result<()> {
         // Run route handler if exists
         let method = req.method();
         let url = req.path();
 
-        if let Some(matched_route) = self.route_handlers.match_route(method, url) {
-            let parameters = matched_route.parameters;
-            let url_parameters = matched_route.url_parameters;
-            let context_req = Request {
-                parameters,
-                url_parameters,
-                req,
-            };
-            (matched_route.handler)(context_req, res)
-        } else {
-            // No route handler found, return 404
-            res.status_code(404, "Not Found");
+        let route_handlers = self.route_handlers.for_host(req.header("host"));
+        let route_match = match (self.slash_policy, canonical_path(url)) {
+            (SlashPolicy::Merge, _) | (_, None) => route_handlers.match_route(method, url),
+            (SlashPolicy::Strict, Some(_)) => RouteMatch::NotFound,
+            (policy, Some(canonical)) => match route_handlers.match_route(method, &canonical) {
+                RouteMatch::NotFound => RouteMatch::NotFound,
+                _ => {
+                    if policy == SlashPolicy::MovedPermanently {
+                        res.status_code(301, "Moved Permanently");
+                    } else {
+                        res.status_code(308, "Permanent Redirect");
+                    }
+                    res.header(format!("Location: {}", canonical));
+                    return Ok(());
+                }
+            },
+        };
+
+        match route_match {
+            RouteMatch::Found(matched_route) => {
+                req.parameters = matched_route.parameters;
+                req.url_parameters = matched_route.url_parameters;
+                for middleware in matched_route.middlewares {
+                    middleware(&req.req, res)?;
+                    if res.is_written() {
+                        return Ok(());
+                    }
+                }
+                // the handler owns its request, the around middleware keep
+                // theirs in case they retry
+                let handler = &matched_route.handler;
+                let next = Next {
+                    middlewares: matched_route.around_middlewares,
+                    endpoint: &|req: &mut Request, res: &mut Response| handler(req.reborrow(), res),
+                };
+                next.run(req, res)
+            }
+            RouteMatch::MethodNotAllowed(allowed) if method == "OPTIONS" => {
+                // No explicit OPTIONS handler, report what the path supports
+                res.status_code(204, "No Content");
+                res.header(format!("Allow: {}", allowed.join(", ")));
+                Ok(())
+            }
+            RouteMatch::MethodNotAllowed(allowed) => {
+                // The path exists under other methods, return 405
+                res.status_code(405, "Method Not Allowed");
+                res.header(format!("Allow: {}", allowed.join(", ")));
+                Ok(())
+            }
+            RouteMatch::NotFound => match &self.fallback {
+                Some(fallback) => {
+                    let query_string = url.split_once('?').map_or("", |(_, query)| query);
+                    req.url_parameters = match parse_query(query_string) {
+                        Ok(url_parameters) => url_parameters,
+                        Err(e) => {
+                            res.status_code(400, "Bad Request");
+                            return res.send(e.to_string());
+                        }
+                    };
+                    fallback(req.reborrow(), res)
+                }
+                None => {
+                    // No route handler found, return 404
+                    res.status_code(404, "Not Found");
+                    Ok(())
+                }
+            },
+            RouteMatch::Invalid(e) => {
+                // The URL could not be decoded, return 400
+                res.status_code(400, "Bad Request");
+                res.send(e.to_string())
+            }
+        }
+    }
+}
+
+static REQUEST_ID_SEED: Lazy<u64> = Lazy::new(|| RandomState::new().build_hasher().finish());
+static REQUEST_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// a random per-process prefix keeps IDs unique across processes
+fn generate_request_id() -> String {
+    let count = REQUEST_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:016x}", *REQUEST_ID_SEED, count)
+}
+
+// IDs end up in headers and logs, so only short visible ASCII is accepted
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::server::testing::send;
+
+    #[test]
+    fn around_middleware_retries_and_rewrites_errors() {
+        let mut app = Server::new();
+        app.around(|req, res, next| {
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                match next.run(req, res) {
+                    Err(_) if attempts < 3 => continue,
+                    Err(e) => {
+                        res.status_code(503, "Service Unavailable");
+                        return res.send(e.to_string());
+                    }
+                    Ok(()) => return Ok(()),
+                }
+            }
+        });
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flaky_calls = Arc::clone(&calls);
+        app.get("/flaky/:id", move |req, res| {
+            match flaky_calls.fetch_add(1, Ordering::Relaxed) {
+                0 | 1 => Err(io::Error::other("flaky")),
+                _ => res.send(format!(
+                    "{} {:?}",
+                    req.parameter("id").unwrap(),
+                    req.url_parameter("a")
+                )),
+            }
+        });
+        app.get("/broken", |_, _| Err(io::Error::other("broken")));
+
+        let res = send(&mut app, "GET /flaky/7?a=b HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body(), "7 Some(\"b\")");
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        let res = send(&mut app, "GET /broken HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 503);
+        assert_eq!(res.header("content-length"), Some("6"));
+        assert_eq!(res.body(), "broken");
+    }
+
+    #[test]
+    fn runs_middleware_and_after_hooks_around_the_handler() {
+        let mut app = Server::new();
+        app.use_middleware(trace("before"));
+        app.use_middleware(|req, res| {
+            if req.header("x-block").is_some() {
+                res.status_code(401, "Unauthorized");
+            }
+            Ok(())
+        });
+        app.use_middleware(trace("before 2"));
+        app.around(|req, res, next| {
+            res.header("X-Trace: around");
+            next.run(req, res)?;
+            res.header("X-Trace: around after");
+            Ok(())
+        });
+        let hooks = Arc::new(AtomicUsize::new(0));
+        let hook_runs = Arc::clone(&hooks);
+        app.after(move |res| {
+            hook_runs.fetch_add(1, Ordering::Relaxed);
+            res.header("X-Trace: after");
+            if res.status() == 401 {
+                res.send("blocked")?;
+            }
+            Ok(())
+        });
+        app.get("/", |_, res| {
+            res.header("X-Trace: handler");
+            res.send("ok")
+        });
+        app.get("/fail", |_, _| Err(io::Error::other("failed")));
+
+        let res = send(&mut app, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            res.header_values("x-trace"),
+            [
+                "before",
+                "before 2",
+                "around",
+                "handler",
+                "around after",
+                "after"
+            ]
+        );
+        assert_eq!(res.body(), "ok");
+
+        // a middleware that writes the response ends the request early
+        let res = send(&mut app, "GET / HTTP/1.1\r\nX-Block: 1\r\n\r\n");
+        assert_eq!(res.status, 401);
+        assert_eq!(res.header_values("x-trace"), ["before", "after"]);
+        assert_eq!(res.body(), "blocked");
+        assert_eq!(hooks.load(Ordering::Relaxed), 2);
+
+        let res = send(&mut app, "GET /fail HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 500);
+        assert_eq!(hooks.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn serves_head_and_options_from_get_routes() {
+        let mut app = Server::new();
+        app.get("/user/:id", |_, res| res.send("hello"));
+
+        let res = send(&mut app, "HEAD /user/42 HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 200);
+        assert_eq!(res.header("content-length"), Some("5"));
+        assert_eq!(res.body(), "");
+
+        let res = send(&mut app, "OPTIONS /user/42 HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 204);
+        assert_eq!(res.header("allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(res.header("content-length"), None);
+        assert_eq!(res.body(), "");
+    }
+
+    // a middleware that adds `X-Trace: name`
+    fn trace(name: &'static str) -> impl Fn(&RawRequest, &mut Response) -> io::Result<()> {
+        move |_, res| {
+            res.header(format!("X-Trace: {}", name));
             Ok(())
         }
     }
+
+    // an around middleware that adds `X-Trace: name`
+    fn wrap(name: &'static str) -> impl Fn(&mut Request, &mut Response, Next) -> io::Result<()> {
+        move |req, res, next| {
+            res.header(format!("X-Trace: {}", name));
+            next.run(req, res)
+        }
+    }
+
+    #[test]
+    fn route_middleware_runs_last_and_only_for_its_route() {
+        let mut app = Server::new();
+        app.use_middleware(trace("global"));
+        app.around(wrap("around"));
+        app.group("/g", |g| {
+            g.middleware(trace("group"));
+            g.get("/with", |_, res| res.send("with"))
+                .with(wrap("with 1"))
+                .with(wrap("with 2"));
+            g.get("/plain", |_, res| res.send("plain"));
+        });
+
+        let res = send(&mut app, "GET /g/with HTTP/1.1\r\n\r\n");
+        assert_eq!(res.body(), "with");
+        assert_eq!(
+            res.header_values("x-trace"),
+            ["global", "around", "group", "with 1", "with 2"]
+        );
+
+        let res = send(&mut app, "GET /g/plain HTTP/1.1\r\n\r\n");
+        assert_eq!(res.body(), "plain");
+        assert_eq!(res.header_values("x-trace"), ["global", "around", "group"]);
+
+        let res = send(&mut app, "POST /g/with HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 405);
+        assert_eq!(res.header_values("x-trace"), ["global", "around"]);
+    }
+
+    #[test]
+    fn groups_share_a_prefix_and_middleware() {
+        let mut app = Server::new();
+        app.group("/api/", |api| {
+            api.middleware(trace("api"));
+            api.get("/users", |_, res| res.send("users"));
+            api.group("v1", |v1| {
+                v1.middleware(trace("v1"));
+                v1.get("/items/:id", |req, res| {
+                    res.send(req.parameter("id").unwrap())
+                });
+            });
+            api.get("/after", |_, res| res.send("after"));
+        });
+        app.get("/other", |_, res| res.send("other"));
+
+        let paths: Vec<_> = app.routes().into_iter().map(|route| route.path).collect();
+        assert_eq!(
+            paths,
+            ["/api/users", "/api/v1/items/:id", "/api/after", "/other"]
+        );
+
+        let res = send(&mut app, "GET /api/v1/items/7 HTTP/1.1\r\n\r\n");
+        assert_eq!(res.body(), "7");
+        assert_eq!(res.header_values("x-trace"), ["api", "v1"]);
+
+        let res = send(&mut app, "GET /api/users HTTP/1.1\r\n\r\n");
+        assert_eq!(res.header_values("x-trace"), ["api"]);
+        let res = send(&mut app, "GET /api/after HTTP/1.1\r\n\r\n");
+        assert_eq!(res.header_values("x-trace"), ["api"]);
+
+        let res = send(&mut app, "GET /other HTTP/1.1\r\n\r\n");
+        assert_eq!(res.body(), "other");
+        assert!(res.header_values("x-trace").is_empty());
+        let res = send(&mut app, "GET /api/v1 HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 404);
+        assert!(res.header_values("x-trace").is_empty());
+    }
+
+    #[test]
+    fn fallback_gets_unmatched_requests_with_their_query() {
+        let mut app = Server::new();
+        app.get("/known", |_, res| res.send("known"));
+        assert_eq!(send(&mut app, "GET /missing HTTP/1.1\r\n\r\n").status, 404);
+
+        app.fallback(|req, res| {
+            res.status_code(404, "Not Found");
+            res.send(format!("{:?}", req.url_parameters_all("tag")))
+        });
+        let res = send(&mut app, "GET /missing?tag=a&tag=b%20c HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 404);
+        assert_eq!(res.body(), r#"["a", "b c"]"#);
+
+        let res = send(&mut app, "GET /missing?tag=%ff HTTP/1.1\r\n\r\n");
+        assert_eq!(res.status, 400);
+        assert!(!res.body().starts_with('['));
+
+        let res = send(&mut app, "GET /known?tag=a HTTP/1.1\r\n\r\n");
+        assert_eq!(res.body(), "known");
+    }
+
+    #[test]
+    fn applies_the_slash_policy() {
+        let mut app = Server::new();
+        app.group("/api", |api| {
+            api.get("/", |_, res| res.send("api"));
+        });
+        assert_eq!(app.routes()[0].path, "/api");
+
+        let get =
+            |app: &mut Server, path: &str| send(app, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+        for path in ["/api", "/api/", "//api"] {
+            assert_eq!(get(&mut app, path).body(), "api");
+        }
+
+        app.slash_policy(SlashPolicy::Strict);
+        assert_eq!(get(&mut app, "/api").body(), "api");
+        assert_eq!(get(&mut app, "/api/").status, 404);
+        assert_eq!(get(&mut app, "//api").status, 404);
+
+        app.slash_policy(SlashPolicy::MovedPermanently);
+        let res = get(&mut app, "/api/?page=2");
+        assert_eq!(res.status, 301);
+        assert_eq!(res.header("location"), Some("/api?page=2"));
+        assert_eq!(get(&mut app, "/missing/").status, 404);
+
+        app.slash_policy(SlashPolicy::PermanentRedirect);
+        let res = get(&mut app, "//api");
+        assert_eq!(res.status, 308);
+        assert_eq!(res.header("location"), Some("/api"));
+        assert_eq!(get(&mut app, "/api").body(), "api");
+    }
+
+    #[test]
+    fn validates_request_ids() {
+        assert!(valid_request_id("f0e1d2c3-b4a5-4697-8899-aabbccddeeff"));
+        assert!(valid_request_id(&"a".repeat(128)));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id(&"a".repeat(129)));
+        assert!(!valid_request_id("a b"));
+        assert!(!valid_request_id("a\tb"));
+        assert!(!valid_request_id("a\r\nSet-Cookie: x"));
+        assert!(!valid_request_id("é"));
+
+        let (first, second) = (generate_request_id(), generate_request_id());
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 32);
+        assert!(first.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert!(valid_request_id(&first));
+    }
 }
@@ -0,0 +1,82 @@
+//! Runs requests through a service without a listening server, for tests.
+
+use std::mem::MaybeUninit;
+
+use bytes::BytesMut;
+use may::net::{TcpListener, TcpStream};
+
+use crate::http::http_server::{serve, HttpService};
+use crate::request::request::{decode, RawRequest, MAX_HEADERS};
+
+/// A response as it was encoded for the client.
+pub(crate) struct Sent {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Sent {
+    /// The value of the only header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let mut values = self
+            .headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name));
+        let value = values.next().map(|(_, value)| value.as_str());
+        assert!(values.next().is_none(), "{} is sent more than once", name);
+        value
+    }
+
+    /// Every value of the headers called `name`, in the order they were sent.
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn body(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+}
+
+/// Decodes `request`, which must be complete, and hands it to `f`.
+pub(crate) fn with_request<R>(request: &str, f: impl FnOnce(RawRequest) -> R) -> R {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut req_buf = BytesMut::from(request.as_bytes());
+    let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+    let req = decode(&mut headers, &mut req_buf, &mut stream)
+        .unwrap()
+        .unwrap();
+    f(req)
+}
+
+/// Serves `request` the way a connection would and parses the response.
+pub(crate) fn send(service: &mut impl HttpService, request: &str) -> Sent {
+    let mut body_buf = BytesMut::new();
+    let mut res_buf = BytesMut::new();
+    with_request(request, |req| {
+        serve(service, req, None, &mut body_buf, &mut res_buf)
+    });
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut res = httparse::Response::new(&mut headers);
+    let len = match res.parse(&res_buf).unwrap() {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => panic!("incomplete response"),
+    };
+    Sent {
+        status: res.code.unwrap(),
+        headers: res
+            .headers
+            .iter()
+            .map(|header| {
+                let value = String::from_utf8(header.value.to_vec()).unwrap();
+                (header.name.to_string(), value)
+            })
+            .collect(),
+        body: res_buf[len..].to_vec(),
+    }
+}